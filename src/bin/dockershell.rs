@@ -1,11 +1,7 @@
 use dockershell::*;
 use clap::{App,Arg};
-use std::fs::File;
-use std::io::BufReader;
-use std::io::BufRead;
-use std::result::Result;
 
-fn main() -> Result<(), std::io::Error> {
+fn main() -> Result<()> {
     let matches = App::new("dockershell")
         .version("0.1")
         .about("A shell with undo.")
@@ -19,45 +15,111 @@ fn main() -> Result<(), std::io::Error> {
        ).arg(
         Arg::with_name("dockerfile")
             .short("f")
+            .value_name("Dockerfile")
             .help("Dockerfile of instructions to pre-run")
             .required(false)
-            .multiple(true),
+            .takes_value(true),
+    ).arg(
+        Arg::with_name("timeout")
+            .long("timeout")
+            .value_name("seconds")
+            .help("Stop commands that run for longer, recording nothing")
+            .required(false)
+            .takes_value(true),
+    ).arg(
+        Arg::with_name("resume")
+            .long("resume")
+            .value_name("session")
+            .help("Session name (or file) to carry on with")
+            .required(false)
+            .takes_value(true)
+            .conflicts_with_all(&["image", "dockerfile", "timeout"]),
     ).get_matches();
 
+    if let Some(name) = matches.value_of("resume") {
+        let session = Session::open(name)?;
+        let runtime = DockerRuntime::connect_with_defaults()?;
+        return resume_from_stdin(&session, &runtime);
+    }
+
     let (lines, image_name) = if let Some(dockerfile) = matches.value_of("dockerfile") {
-        let file = File::open(dockerfile)?;
-        let l = parse_dockerfile(BufReader::new(file).lines());
-        let i = l[0][1].clone();
+        let text = std::fs::read_to_string(dockerfile)?;
+        let parsed = match dockerfile::parse(&text) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                eprintln!("{}:{}", dockerfile, err);
+                std::process::exit(1);
+            }
+        };
+        let stage = match parsed.stage() {
+            Ok(stage) => stage,
+            Err(err) => {
+                eprintln!("{}:{}", dockerfile, err);
+                std::process::exit(1);
+            }
+        };
+        let i = parsed.base_image().unwrap();
+        let context = std::path::Path::new(dockerfile).parent().unwrap();
+        let mut l = vec![Instruction::from_image(&i)];
+        for node in &stage[1..] {
+            match Instruction::from_node(node) {
+                Ok(instruction) => l.push(in_context(instruction, context)),
+                Err(err) => {
+                    eprintln!("{}:{}", dockerfile, err);
+                    std::process::exit(1);
+                }
+            }
+        }
         (l, i)
     } else {
         let image_name = matches.value_of("image").unwrap_or("alpine:edge").to_owned();
-        (vec![vec!["FROM".to_owned(), image_name.clone()]], image_name)
+        (vec![Instruction::from_image(&image_name)], image_name)
     };
 
-    let state = State {
+    let timeout = matches.value_of("timeout").map(|secs| match secs.parse() {
+        Ok(secs) => std::time::Duration::from_secs(secs),
+        Err(_) => {
+            eprintln!("--timeout: {} is not a number of seconds", secs);
+            std::process::exit(1);
+        }
+    });
+
+    let mut state = State {
         debug: false,
         tty: true,
         lines,
         image_name,
         pwd: String::new(),
+        oldpwd: String::new(),
+        dir_stack: vec![],
+        layer_changes: Default::default(),
+        ignore: Default::default(),
+        timeout,
         shell: "/bin/sh".to_owned(),
+        editor: None,
     };
 
-    interpreter_loop_from_stdin(state).unwrap();
-    Ok(())
+    let runtime = DockerRuntime::connect_with_defaults()?;
+    state.ignore = IgnoreRules::for_image(&runtime, &state)?;
+    interpreter_loop_from_stdin(state, &runtime)
 }
 
-fn parse_dockerfile<T>(lines: T) -> Vec<Vec<String>>
-    where T: IntoIterator<Item=Result<String, std::io::Error>> {
-
-    let mut results = vec![];
-
-    for line in lines {
-        // We cheat - not actually splitting into more than two parts...
-        let raw = line.unwrap();
-        let parts : Vec<_> = raw.split(" ").collect();
-        results.push(vec![ parts[0].to_owned(), raw[parts[0].len()..].to_owned() ]);
+/// With the sources of a `COPY` taken from `context`, the Dockerfile's directory.
+fn in_context(instruction: Instruction, context: &std::path::Path) -> Instruction {
+    match instruction {
+        Instruction::Copy { flags, sources, dest, heredocs }
+            if !flags.iter().any(|flag| flag.starts_with("--from=")) =>
+        {
+            Instruction::Copy {
+                sources: sources
+                    .iter()
+                    .map(|source| context.join(source).to_string_lossy().into_owned())
+                    .collect(),
+                flags,
+                dest,
+                heredocs,
+            }
+        }
+        instruction => instruction,
     }
-
-    results
-}
\ No newline at end of file
+}
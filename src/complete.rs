@@ -0,0 +1,265 @@
+//! Tab completion at the prompt: built-ins, commands on the image's `PATH`
+//! and paths in the image. What an image has is listed once per image id.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use rustyline::completion::{Completer, FilenameCompleter};
+use rustyline::Context;
+
+use super::error::Result;
+use super::runtime::ContainerRuntime;
+use super::scratch::ScratchContainers;
+use super::{in_container, Recording, State};
+
+pub(crate) const BUILT_INS: &[&str] = &[
+    "branches",
+    "checkout",
+    "checkpoint",
+    "copy",
+    "debug",
+    "diff",
+    "edit",
+    "exit",
+    "get",
+    "ignore",
+    "image",
+    "layers",
+    "redo",
+    "timeout",
+    "undo",
+];
+
+/// What has been listed of one image.
+#[derive(Debug, Default)]
+struct Listing {
+    executables: Option<Vec<String>>,
+    /// Directory listings, by absolute path.
+    dirs: HashMap<String, Vec<String>>,
+}
+
+/// Completes what is typed at the prompt in the image being worked on.
+pub(crate) struct Completions<'a> {
+    runtime: &'a dyn ContainerRuntime,
+    /// Containers of its own to list images in, so as to leave those
+    /// commands run in alone.
+    scratch: ScratchContainers,
+    state: Option<State>,
+    /// By image id.
+    listings: RefCell<HashMap<String, Listing>>,
+    /// For the host side of `copy` and `get`.
+    host: FilenameCompleter,
+}
+
+impl<'a> Completions<'a> {
+    pub(crate) fn new(runtime: &'a dyn ContainerRuntime) -> Completions<'a> {
+        Completions {
+            runtime,
+            scratch: ScratchContainers::new(),
+            state: None,
+            listings: RefCell::new(HashMap::new()),
+            host: FilenameCompleter::new(),
+        }
+    }
+
+    /// Completes in `state` from now on.
+    pub(crate) fn set_state(&mut self, state: &State) {
+        let image = self.state.as_ref().map(|state| state.image_name.as_str());
+        if image != Some(state.image_name.as_str()) {
+            // Listings are kept, but the last image needn't keep a container.
+            let _ = self.scratch.remove_all(self.runtime);
+        }
+        self.state = Some(State {
+            // Listing mustn't print over the prompt.
+            debug: false,
+            ..state.clone()
+        });
+    }
+
+    /// Removes the containers it listed images in.
+    pub(crate) fn remove_containers(&self) -> Result<()> {
+        self.scratch.remove_all(self.runtime)
+    }
+
+    /// Where the word `pos` is at starts, and what it could be.
+    pub(crate) fn candidates(&self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        let mut start = line[..pos]
+            .rfind(&[' ', '\t'][..])
+            .map_or(0, |space| space + 1);
+        let args: Vec<&str> = Recording::of(&line[..start]).1.split_whitespace().collect();
+        let mut word = &line[start..pos];
+        if args.is_empty() {
+            // A `!!` or `?` typed right before the command.
+            let command = Recording::of(word).1;
+            start += word.len() - command.len();
+            word = command;
+        }
+
+        let mut candidates = match (args.first(), args.len()) {
+            (None, _) if !word.contains('/') => {
+                let mut commands = self.executables();
+                commands.extend(BUILT_INS.iter().map(|name| name.to_string()));
+                commands.retain(|command| command.starts_with(word));
+                commands
+            }
+            (Some(&"copy"), _) | (Some(&"get"), 2) => return self.host_paths(line, pos),
+            (Some(&"ignore"), 1) => vec!["on".to_owned(), "off".to_owned()],
+            (Some(&"timeout"), 1) => vec!["off".to_owned()],
+            (None, _) | (Some(&"edit"), 1) | (Some(&"get"), 1) => self.paths(word),
+            (Some(command), _) if BUILT_INS.contains(command) => vec![],
+            _ => self.paths(word),
+        };
+        candidates.retain(|candidate| candidate.starts_with(word));
+        candidates.sort();
+        candidates.dedup();
+        (start, candidates)
+    }
+
+    /// Paths in the image that start with `word`, relative to the working
+    /// directory unless absolute. Dotfiles only if `word` names one.
+    fn paths(&self, word: &str) -> Vec<String> {
+        let state = match &self.state {
+            Some(state) => state,
+            None => return vec![],
+        };
+        let (dir, name) = word.split_at(word.rfind('/').map_or(0, |slash| slash + 1));
+        let listed = if dir.is_empty() {
+            state.pwd.clone()
+        } else {
+            in_container(state, dir)
+        };
+        self.dir(&listed)
+            .into_iter()
+            .filter(|entry| {
+                entry.starts_with(name) && (name.starts_with('.') || !entry.starts_with('.'))
+            })
+            .map(|entry| format!("{}{}", dir, entry))
+            .collect()
+    }
+
+    fn host_paths(&self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        match self.host.complete_path(line, pos) {
+            Ok((start, pairs)) => (
+                start,
+                pairs.into_iter().map(|pair| pair.replacement).collect(),
+            ),
+            Err(_) => (pos, vec![]),
+        }
+    }
+
+    fn executables(&self) -> Vec<String> {
+        let id = match self.image_id() {
+            Some(id) => id,
+            None => return vec![],
+        };
+        let mut listings = self.listings.borrow_mut();
+        let listing = listings.entry(id).or_default();
+        if listing.executables.is_none() {
+            listing.executables = self
+                .container()
+                .and_then(|container| self.runtime.executables(&container))
+                .ok();
+        }
+        listing.executables.clone().unwrap_or_default()
+    }
+
+    /// What is in the absolute directory `path`.
+    fn dir(&self, path: &str) -> Vec<String> {
+        let id = match self.image_id() {
+            Some(id) => id,
+            None => return vec![],
+        };
+        let mut listings = self.listings.borrow_mut();
+        let listing = listings.entry(id).or_default();
+        if !listing.dirs.contains_key(path) {
+            let listed = self
+                .container()
+                .and_then(|container| self.runtime.list_dir(&container, path));
+            if let Ok(names) = listed {
+                listing.dirs.insert(path.to_owned(), names);
+            }
+        }
+        listing.dirs.get(path).cloned().unwrap_or_default()
+    }
+
+    /// None while there is no image to look in, e.g. as it is still building.
+    fn image_id(&self) -> Option<String> {
+        let state = self.state.as_ref()?;
+        let info = self.runtime.inspect_image(&state.image_name).ok()?;
+        Some(info.id)
+    }
+
+    fn container(&self) -> Result<String> {
+        // Only called once there is a state.
+        self.scratch.get(self.runtime, self.state.as_ref().unwrap())
+    }
+}
+
+impl<'a> Completer for Completions<'a> {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.candidates(line, pos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Completions;
+    use crate::{FakeRuntime, State};
+
+    #[test]
+    fn commands_and_paths_are_completed() {
+        let runtime = FakeRuntime::new()
+            .file("/bin/ls", "")
+            .file("/usr/bin/less", "")
+            .file("/usr/lib/libc.so", "")
+            .file("/usr/lib/.hidden", "")
+            .file("/usr/local/share/doc", "");
+        let mut completions = Completions::new(&runtime);
+        completions.set_state(&State {
+            pwd: "/usr".to_owned(),
+            ..State::test()
+        });
+        let complete = |line: &str| completions.candidates(line, line.len());
+
+        assert_eq!(
+            complete("l"),
+            (
+                0,
+                vec!["layers".to_owned(), "less".to_owned(), "ls".to_owned()]
+            )
+        );
+        assert_eq!(complete("!!le"), (2, vec!["less".to_owned()]));
+        assert_eq!(
+            complete("cat l"),
+            (4, vec!["lib/".to_owned(), "local/".to_owned()])
+        );
+        assert_eq!(complete("cat lib/"), (4, vec!["lib/libc.so".to_owned()]));
+        assert_eq!(complete("cat lib/."), (4, vec!["lib/.hidden".to_owned()]));
+        assert_eq!(
+            complete("? cd /usr/lo"),
+            (5, vec!["/usr/local/".to_owned()])
+        );
+        assert_eq!(
+            complete("edit local/share/"),
+            (5, vec!["local/share/doc".to_owned()])
+        );
+        assert_eq!(
+            complete("ignore o"),
+            (7, vec!["off".to_owned(), "on".to_owned()])
+        );
+        assert_eq!(complete("layers l"), (7, vec![]));
+
+        // Listed once, in one container.
+        assert_eq!(complete("cat lib/"), (4, vec!["lib/libc.so".to_owned()]));
+        assert_eq!(runtime.containers_created(), 1);
+        completions.remove_containers().unwrap();
+        assert_eq!(runtime.containers(), 0);
+    }
+}
@@ -0,0 +1,228 @@
+//! Private build contexts, so building never touches the user's working directory,
+//! and the files the recipe's `COPY` instructions copy from.
+
+use std::fs::{self, DirBuilder, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use tar::{Builder, Header};
+
+use super::error::Result;
+use super::exec::unique_name;
+use super::instruction::{to_dockerfile, Instruction};
+
+/// A tar'd build context in a temporary directory of its own,
+/// which is removed again on drop.
+pub(crate) struct BuildContext {
+    dir: PathBuf,
+    tar: PathBuf,
+}
+
+impl BuildContext {
+    /// A context holding the given Dockerfile and everything in `managed`.
+    pub(crate) fn new(dockerfile: &str, managed: &ManagedContext) -> Result<BuildContext> {
+        let dir = private_dir()?;
+        let context = BuildContext {
+            tar: dir.join("context.tar"),
+            dir,
+        };
+
+        let mut header = Header::new_gnu();
+        header.set_path("Dockerfile")?;
+        header.set_size(dockerfile.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut tar = Builder::new(vec![]);
+        tar.append(&header, dockerfile.as_bytes())?;
+        if managed.dir.is_dir() {
+            tar.append_dir_all(".", &managed.dir)?;
+        }
+
+        File::create(&context.tar)?.write_all(&tar.into_inner()?)?;
+        Ok(context)
+    }
+
+    /// The tar to hand to the build API.
+    pub(crate) fn tar(&self) -> &Path {
+        &self.tar
+    }
+}
+
+impl Drop for BuildContext {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Where the files that `COPY` instructions copy are kept. A session's are
+/// kept with it so that its images can be rebuilt when it is resumed.
+pub struct ManagedContext {
+    dir: PathBuf,
+    /// Removed on drop.
+    temporary: bool,
+}
+
+impl ManagedContext {
+    /// Kept in `dir`, which is created once there is something to keep.
+    pub fn at<P: AsRef<Path>>(dir: P) -> ManagedContext {
+        ManagedContext {
+            dir: dir.as_ref().to_owned(),
+            temporary: false,
+        }
+    }
+
+    /// Kept in a temporary directory for as long as this lives.
+    pub fn temporary() -> Result<ManagedContext> {
+        Ok(ManagedContext {
+            dir: private_dir()?,
+            temporary: true,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// A new, empty directory for the files of one instruction, e.g. `edit-3`.
+    /// Returns its path relative to the context, and where it is.
+    pub(crate) fn new_dir(&self, purpose: &str) -> Result<(String, PathBuf)> {
+        fs::create_dir_all(&self.dir)?;
+        let mut n = 1;
+        loop {
+            let name = format!("{}-{}", purpose, n);
+            let dir = self.dir.join(&name);
+            match fs::create_dir(&dir) {
+                Ok(()) => return Ok((name, dir)),
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => n += 1,
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// Copies a host file or directory in, giving everything `mode` if set.
+    /// Returns the path to give `COPY`.
+    pub(crate) fn add(&self, source: &Path, mode: Option<u32>) -> Result<String> {
+        // So that e.g. `.` gets the name of the directory it is.
+        let source = source.canonicalize()?;
+        let name = match source.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => "root".to_owned(),
+        };
+        let (dir_name, dir) = self.new_dir("copy")?;
+        if let Err(err) = copy_tree(&source, &dir.join(&name), mode) {
+            let _ = fs::remove_dir_all(&dir);
+            return Err(err);
+        }
+        Ok(format!("{}/{}", dir_name, name))
+    }
+
+    /// Every regular file of `source` (as returned by `add`) and its size,
+    /// by path relative to it: "" if it is a file.
+    pub(crate) fn file_sizes(&self, source: &str) -> Result<Vec<(String, u64)>> {
+        let mut sizes = vec![];
+        let mut pending = vec![PathBuf::new()];
+        while let Some(relative) = pending.pop() {
+            let mut path = self.dir.join(source);
+            // Joining "" would add a trailing slash.
+            if relative != PathBuf::new() {
+                path.push(&relative);
+            }
+            let metadata = fs::metadata(&path)?;
+            if metadata.is_dir() {
+                for entry in fs::read_dir(&path)? {
+                    pending.push(relative.join(entry?.file_name()));
+                }
+            } else {
+                sizes.push((relative.to_string_lossy().into_owned(), metadata.len()));
+            }
+        }
+        sizes.sort();
+        Ok(sizes)
+    }
+
+    /// Sources of `lines`' `COPY`s that are kept here.
+    pub(crate) fn sources<'a>(&self, lines: &'a [Instruction]) -> Vec<&'a str> {
+        lines
+            .iter()
+            .filter_map(|line| match line {
+                Instruction::Copy { flags, sources, .. }
+                    if !flags.iter().any(|flag| flag.starts_with("--from")) =>
+                {
+                    Some(sources)
+                }
+                _ => None,
+            })
+            .flatten()
+            .map(|source| source.as_str())
+            .filter(|source| self.dir.join(source).exists())
+            .collect()
+    }
+
+    /// Writes `lines` as a Dockerfile into `to`, along with the files
+    /// it copies from here, so that it can be built from there.
+    pub(crate) fn export(&self, lines: &[Instruction], to: &Path) -> Result<()> {
+        fs::create_dir_all(to)?;
+        fs::write(to.join("Dockerfile"), to_dockerfile(lines) + "\n")?;
+        for source in self.sources(lines) {
+            let dest = to.join(source);
+            if dest.exists() {
+                continue;
+            }
+            fs::create_dir_all(dest.parent().unwrap())?;
+            copy_tree(&self.dir.join(source), &dest, None)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ManagedContext {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+/// A new directory under the system temp dir that only we can read.
+pub(crate) fn private_dir() -> Result<PathBuf> {
+    let mut builder = DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    loop {
+        let name = format!("dockershell-{}-{}", process::id(), unique_name());
+        let dir = std::env::temp_dir().join(name);
+        match builder.create(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Copies a file, or a directory and everything in it, following symlinks.
+/// Everything copied gets `mode` if given, else keeps its own.
+pub(crate) fn copy_tree(from: &Path, to: &Path, mode: Option<u32>) -> Result<()> {
+    let metadata = fs::metadata(from)?;
+    if metadata.is_dir() {
+        fs::create_dir(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &to.join(entry.file_name()), mode)?;
+        }
+        fs::set_permissions(to, metadata.permissions())?;
+    } else {
+        fs::copy(from, to)?;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Some(mode) = mode {
+            fs::set_permissions(to, fs::Permissions::from_mode(mode))?;
+        }
+    }
+    Ok(())
+}
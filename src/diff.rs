@@ -0,0 +1,170 @@
+//! What each recorded layer did to the filesystem, and the `diff` built-in.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::error::{Error, Result};
+use super::runtime::ChangeKind;
+use super::{Instruction, State};
+
+/// One path a layer added, changed or deleted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileChange {
+    pub path: String,
+    pub kind: ChangeKind,
+    /// Size afterwards, if it is a regular file.
+    #[serde(default)]
+    pub size: Option<u64>,
+}
+
+impl FileChange {
+    pub fn new(path: &str, kind: ChangeKind, size: Option<u64>) -> FileChange {
+        FileChange {
+            path: path.to_owned(),
+            kind,
+            size,
+        }
+    }
+}
+
+/// `diff`: the last layer that changed files. `diff N`: layer N.
+/// `diff A B`: everything layers A+1 to B did, together.
+/// Layers are numbered as by `layers`.
+pub(crate) fn show(state: &State, args: &[usize]) -> Result<()> {
+    let last = state.lines.len() - 1;
+    let check = |layer: usize| {
+        if layer > last {
+            Err(Error::BadArgument(format!(
+                "diff: no layer {} (see `layers`)",
+                layer
+            )))
+        } else {
+            Ok(layer)
+        }
+    };
+    match *args {
+        [] => match state.layer_changes.keys().next_back() {
+            Some(&layer) => show_layer(state, layer),
+            None => println!("No files changed yet"),
+        },
+        [layer] => show_layer(state, check(layer)?),
+        [from, to] if from < to => {
+            println!("Layers {} to {}:", from + 1, check(to)?);
+            let changes = merge(
+                state
+                    .layer_changes
+                    .range(from + 1..=to)
+                    .map(|(_, changes)| &changes[..]),
+            );
+            print_changes(&changes);
+        }
+        _ => {
+            return Err(Error::BadArgument(
+                "usage: diff [layer] | diff <from-layer> <to-layer>".to_owned(),
+            ))
+        }
+    }
+    Ok(())
+}
+
+fn show_layer(state: &State, layer: usize) {
+    println!("{}: {}", layer, state.lines[layer]);
+    match (state.layer_changes.get(&layer), &state.lines[layer]) {
+        (Some(changes), _) => print_changes(changes),
+        // Rebuilt after an undo below it, say.
+        (None, Instruction::Run { .. }) | (None, Instruction::Copy { .. }) => {
+            println!("  (changes not known)")
+        }
+        (None, _) => println!("  (no files changed)"),
+    }
+}
+
+fn print_changes(changes: &[FileChange]) {
+    if changes.is_empty() {
+        println!("  (no files changed)");
+    }
+    for change in changes {
+        let kind = match change.kind {
+            ChangeKind::Added => 'A',
+            ChangeKind::Modified => 'C',
+            ChangeKind::Deleted => 'D',
+        };
+        match change.size {
+            Some(size) => println!("  {} {} ({})", kind, change.path, human_size(size)),
+            None => println!("  {} {}", kind, change.path),
+        }
+    }
+}
+
+/// What `layers` did together, by path: a file added then changed was added,
+/// one added then deleted never was.
+pub(crate) fn merge<'a, I: IntoIterator<Item = &'a [FileChange]>>(layers: I) -> Vec<FileChange> {
+    let mut merged: BTreeMap<&str, FileChange> = BTreeMap::new();
+    for change in layers.into_iter().flatten() {
+        let kind = match (
+            merged.get(change.path.as_str()).map(|c| c.kind),
+            change.kind,
+        ) {
+            (Some(ChangeKind::Added), ChangeKind::Deleted) => {
+                merged.remove(change.path.as_str());
+                continue;
+            }
+            (Some(ChangeKind::Added), _) => ChangeKind::Added,
+            (Some(ChangeKind::Deleted), ChangeKind::Added) => ChangeKind::Modified,
+            (_, kind) => kind,
+        };
+        merged.insert(
+            &change.path,
+            FileChange {
+                kind,
+                ..change.clone()
+            },
+        );
+    }
+    merged.into_iter().map(|entry| entry.1).collect()
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.;
+    let mut unit = 0;
+    while size >= 1024. && unit < UNITS.len() - 1 {
+        size /= 1024.;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{merge, FileChange};
+    use crate::runtime::ChangeKind::*;
+
+    #[test]
+    fn merging_keeps_the_net_change() {
+        let first = [
+            FileChange::new("/a", Added, Some(1)),
+            FileChange::new("/b", Added, Some(1)),
+            FileChange::new("/c", Deleted, None),
+            FileChange::new("/d", Modified, Some(1)),
+        ];
+        let second = [
+            FileChange::new("/a", Modified, Some(2)),
+            FileChange::new("/b", Deleted, None),
+            FileChange::new("/c", Added, Some(2)),
+            FileChange::new("/d", Deleted, None),
+        ];
+        assert_eq!(
+            merge(vec![&first[..], &second[..]]),
+            vec![
+                FileChange::new("/a", Added, Some(2)),
+                FileChange::new("/c", Modified, Some(2)),
+                FileChange::new("/d", Deleted, None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,190 @@
+//! Engine API calls dockworker doesn't cover, made over the daemon socket directly.
+
+use std::env;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+use serde_json::Value;
+
+use super::error::{Error, Result};
+
+const DEFAULT_DOCKER_HOST: &str = "unix:///var/run/docker.sock";
+
+/// Sends one request and returns the JSON it answers with (`Null` if none).
+pub(crate) fn request(method: &str, path: &str, body: Option<&Value>) -> Result<Value> {
+    let body = body.map(|b| b.to_string()).unwrap_or_default();
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: docker\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method,
+        path,
+        body.len(),
+        body
+    );
+
+    let mut response = vec![];
+    let mut stream = connect()?;
+    stream.write_all(request.as_bytes())?;
+    stream.read_to_end(&mut response)?;
+    let response = String::from_utf8(response)?;
+
+    let (head, body) = split_response(&response)?;
+    let status: u16 = head
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| Error::Docker(format!("bad response: {}", head)))?;
+    let chunked = head.lines().any(|line| {
+        let line = line.to_ascii_lowercase();
+        line.starts_with("transfer-encoding:") && line.contains("chunked")
+    });
+    let body = if chunked {
+        dechunk(body)?
+    } else {
+        body.to_owned()
+    };
+
+    let json = if body.trim().is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_str(&body)
+            .map_err(|err| Error::Docker(format!("bad response: {}", err)))?
+    };
+    if (200..300).contains(&status) {
+        return Ok(json);
+    }
+    let message = json["message"].as_str().unwrap_or(&body).to_owned();
+    Err(if message.contains("No such container") {
+        Error::ContainerNotFound(message)
+    } else if message.contains("No such image") {
+        Error::ImageMissing(message)
+    } else {
+        Error::Docker(message)
+    })
+}
+
+/// Sends a request that docker answers by handing over the connection,
+/// e.g. to attach to an exec. Returns its output and input ends.
+pub(crate) fn upgrade(
+    path: &str,
+    body: &Value,
+) -> Result<(Box<dyn Read + Send>, Box<dyn Write + Send>)> {
+    let body = body.to_string();
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: docker\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: Upgrade\r\nUpgrade: tcp\r\n\r\n{}",
+        path,
+        body.len(),
+        body
+    );
+
+    let mut stream = connect()?;
+    stream.write_all(request.as_bytes())?;
+    // Byte at a time, so nothing after the head ends up in a buffer.
+    let mut head = vec![];
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0u8];
+        if stream.read(&mut byte)? == 0 {
+            return Err(Error::Docker("truncated response".to_owned()));
+        }
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head)?;
+    let status = head.split_whitespace().nth(1).unwrap_or_default();
+    if status != "101" && status != "200" {
+        let mut rest = String::new();
+        let _ = stream.read_to_string(&mut rest);
+        return Err(Error::Docker(format!("{} {}", head.trim(), rest.trim())));
+    }
+    let input = stream.try_clone()?;
+    Ok((Box::new(stream), Box::new(input)))
+}
+
+/// Percent-encodes a query string value.
+pub(crate) fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+trait Stream: Read + Write + Send {
+    /// Another handle on the same connection.
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>>;
+}
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+}
+
+#[cfg(unix)]
+impl Stream for UnixStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(UnixStream::try_clone(self)?))
+    }
+}
+
+/// Same `DOCKER_HOST` rules as dockworker, minus TLS.
+fn connect() -> Result<Box<dyn Stream>> {
+    let host = env::var("DOCKER_HOST").unwrap_or_else(|_| DEFAULT_DOCKER_HOST.to_owned());
+    let unreachable = |err: io::Error| Error::DaemonConnection(format!("{}: {}", host, err));
+    if let Some(path) = host.strip_prefix("unix://") {
+        connect_unix(path).map_err(unreachable)
+    } else if let (Some(addr), Err(_)) =
+        (host.strip_prefix("tcp://"), env::var("DOCKER_TLS_VERIFY"))
+    {
+        let stream = TcpStream::connect(addr).map_err(unreachable)?;
+        Ok(Box::new(stream))
+    } else {
+        Err(Error::DaemonConnection(format!(
+            "{}: only unix sockets and plain tcp are supported",
+            host
+        )))
+    }
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &str) -> io::Result<Box<dyn Stream>> {
+    Ok(Box::new(UnixStream::connect(path)?))
+}
+
+#[cfg(not(unix))]
+fn connect_unix(_path: &str) -> io::Result<Box<dyn Stream>> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "unix sockets are not supported here",
+    ))
+}
+
+fn split_response(response: &str) -> Result<(&str, &str)> {
+    let end = response
+        .find("\r\n\r\n")
+        .ok_or_else(|| Error::Docker("truncated response".to_owned()))?;
+    Ok((&response[..end], &response[end + 4..]))
+}
+
+fn dechunk(mut body: &str) -> Result<String> {
+    let truncated = || Error::Docker("truncated response".to_owned());
+    let mut out = String::new();
+    loop {
+        let line_end = body.find("\r\n").ok_or_else(truncated)?;
+        let size = usize::from_str_radix(body[..line_end].trim(), 16)
+            .map_err(|_| Error::Docker("bad chunk size".to_owned()))?;
+        if size == 0 {
+            return Ok(out);
+        }
+        let start = line_end + 2;
+        out.push_str(body.get(start..start + size).ok_or_else(truncated)?);
+        body = body.get(start + size + 2..).ok_or_else(truncated)?;
+    }
+}
@@ -0,0 +1,388 @@
+//! Dockerfile parser.
+//!
+//! Produces one `Node` per instruction, keeping enough of the source
+//! (flags, shell vs exec form, heredoc bodies, line span) to write it back out.
+
+use std::fmt;
+
+const KEYWORDS: &[&str] = &[
+    "ADD",
+    "ARG",
+    "CMD",
+    "COPY",
+    "ENTRYPOINT",
+    "ENV",
+    "EXPOSE",
+    "FROM",
+    "HEALTHCHECK",
+    "LABEL",
+    "MAINTAINER",
+    "ONBUILD",
+    "RUN",
+    "SHELL",
+    "STOPSIGNAL",
+    "USER",
+    "VOLUME",
+    "WORKDIR",
+];
+
+/// Instructions that take `--flag` options before their arguments.
+const TAKES_FLAGS: &[&str] = &["ADD", "COPY", "FROM", "HEALTHCHECK", "RUN"];
+
+/// Instructions that may be written in JSON exec form.
+const TAKES_EXEC_FORM: &[&str] = &["ADD", "CMD", "COPY", "ENTRYPOINT", "RUN", "SHELL", "VOLUME"];
+
+/// Instructions that may carry heredocs.
+const TAKES_HEREDOCS: &[&str] = &["ADD", "COPY", "RUN"];
+
+/// Lines (1-based, inclusive) an instruction was read from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub start_line: usize,
+    pub end_line: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Arguments {
+    /// `RUN apk add curl`
+    Shell(String),
+    /// `RUN ["apk", "add", "curl"]`
+    Exec(Vec<String>),
+}
+
+impl fmt::Display for Arguments {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Arguments::Shell(text) => write!(f, "{}", text),
+            Arguments::Exec(args) => {
+                let quoted: Vec<String> = args.iter().map(|arg| json_string(arg)).collect();
+                write!(f, "[{}]", quoted.join(", "))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heredoc {
+    /// Delimiter as written, without `<<`, `-` or quotes.
+    pub delimiter: String,
+    /// Body lines, each terminated by `\n`.
+    pub body: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    /// Upper-cased instruction name.
+    pub keyword: String,
+    /// Leading options such as `--chown=app:app`, as written.
+    pub flags: Vec<String>,
+    pub args: Arguments,
+    pub heredocs: Vec<Heredoc>,
+    pub span: Span,
+}
+
+impl fmt::Display for Node {
+    /// Single logical line (continuations joined), followed by any heredoc bodies.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.keyword)?;
+        for flag in &self.flags {
+            write!(f, " {}", flag)?;
+        }
+        write!(f, " {}", self.args)?;
+        for heredoc in &self.heredocs {
+            write!(f, "\n{}{}", heredoc.body, heredoc.delimiter)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dockerfile {
+    /// Parser directives such as `# syntax=docker/dockerfile:1`, as (key, value).
+    pub directives: Vec<(String, String)>,
+    pub escape: char,
+    pub nodes: Vec<Node>,
+}
+
+impl Dockerfile {
+    /// Instructions of its build stage, starting with its `FROM`. A session
+    /// has one image to work on, so there can't be more stages than one.
+    pub fn stage(&self) -> Result<&[Node], ParseError> {
+        let mut froms = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| node.keyword == "FROM");
+        let start = froms.next().map_or(0, |(i, _)| i);
+        match froms.next() {
+            Some((_, node)) => error(
+                node.span.start_line,
+                "FROM of a second build stage (only one is supported)".to_owned(),
+            ),
+            None => Ok(&self.nodes[start..]),
+        }
+    }
+
+    /// Image of the stage's `FROM`, with defaults of `ARG`s declared
+    /// before it substituted. None if there is more than one stage.
+    pub fn base_image(&self) -> Option<String> {
+        let from = self.stage().ok()?.first()?;
+        let image = from.args.to_string();
+        let mut image = image.split_whitespace().next()?.to_owned();
+
+        for node in self.nodes.iter().take_while(|node| node.keyword == "ARG") {
+            let decl = node.args.to_string();
+            let mut parts = decl.splitn(2, '=');
+            let name = parts.next().unwrap_or_default().trim();
+            if let Some(default) = parts.next() {
+                let default = default.trim().trim_matches('"');
+                image = image
+                    .replace(&format!("${{{}}}", name), default)
+                    .replace(&format!("${}", name), default);
+            }
+        }
+        Some(image)
+    }
+}
+
+/// What went wrong and on which (1-based) line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+fn error<T>(line: usize, message: String) -> Result<T, ParseError> {
+    Err(ParseError { line, message })
+}
+
+pub fn parse(text: &str) -> Result<Dockerfile, ParseError> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut i = 0;
+
+    // Parser directives are only recognised before anything else, comments included.
+    let mut directives = vec![];
+    let mut escape = '\\';
+    while i < lines.len() {
+        match parse_directive(lines[i]) {
+            Some((key, value)) => {
+                if directives.iter().any(|(k, _)| *k == key) {
+                    return error(i + 1, format!("directive {} given twice", key));
+                }
+                if key == "escape" {
+                    escape = match value.as_str() {
+                        "\\" => '\\',
+                        "`" => '`',
+                        _ => return error(i + 1, format!("invalid escape token {:?}", value)),
+                    };
+                }
+                directives.push((key, value));
+                i += 1;
+            }
+            None => break,
+        }
+    }
+
+    let mut nodes: Vec<Node> = vec![];
+    while i < lines.len() {
+        if is_blank_or_comment(lines[i]) {
+            i += 1;
+            continue;
+        }
+
+        let start_line = i + 1;
+        let mut logical = String::new();
+        loop {
+            let line = lines[i].trim_end();
+            i += 1;
+            if line.ends_with(escape) {
+                logical.push_str(&line[..line.len() - escape.len_utf8()]);
+                // Comments and empty lines inside a continuation are dropped.
+                while i < lines.len() && is_blank_or_comment(lines[i]) {
+                    i += 1;
+                }
+                if i == lines.len() {
+                    break;
+                }
+            } else {
+                logical.push_str(line);
+                break;
+            }
+        }
+        let mut end_line = i;
+
+        let logical = logical.trim();
+        let (word, rest) = match logical.find(char::is_whitespace) {
+            Some(idx) => (&logical[..idx], logical[idx..].trim_start()),
+            None => (logical, ""),
+        };
+        let keyword = word.to_uppercase();
+        if !KEYWORDS.contains(&keyword.as_str()) {
+            return error(start_line, format!("unknown instruction: {}", word));
+        }
+        if keyword != "ARG" && keyword != "FROM" && !nodes.iter().any(|n| n.keyword == "FROM") {
+            return error(
+                start_line,
+                format!("{} before FROM (only ARG may precede FROM)", keyword),
+            );
+        }
+
+        let mut rest = rest;
+        let mut flags = vec![];
+        if TAKES_FLAGS.contains(&keyword.as_str()) {
+            while rest.starts_with("--") {
+                let idx = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                flags.push(rest[..idx].to_owned());
+                rest = rest[idx..].trim_start();
+            }
+        }
+
+        let args = if rest.starts_with('[') && TAKES_EXEC_FORM.contains(&keyword.as_str()) {
+            // Not valid JSON means docker falls back to shell form.
+            match serde_json::from_str::<Vec<String>>(rest) {
+                Ok(args) => Arguments::Exec(args),
+                Err(_) => Arguments::Shell(rest.to_owned()),
+            }
+        } else {
+            Arguments::Shell(rest.to_owned())
+        };
+
+        let mut heredocs = vec![];
+        if let Arguments::Shell(ref text) = args {
+            if TAKES_HEREDOCS.contains(&keyword.as_str()) {
+                for (delimiter, strip_tabs) in heredoc_delimiters(text) {
+                    let mut body = String::new();
+                    loop {
+                        if i == lines.len() {
+                            return error(
+                                start_line,
+                                format!("unterminated heredoc, expected {}", delimiter),
+                            );
+                        }
+                        let line = lines[i];
+                        i += 1;
+                        let line = if strip_tabs {
+                            line.trim_start_matches('\t')
+                        } else {
+                            line
+                        };
+                        if line == delimiter {
+                            break;
+                        }
+                        body.push_str(line);
+                        body.push('\n');
+                    }
+                    heredocs.push(Heredoc { delimiter, body });
+                }
+                end_line = i;
+            }
+        }
+
+        if rest.is_empty() {
+            return error(start_line, format!("{} requires arguments", keyword));
+        }
+
+        nodes.push(Node {
+            keyword,
+            flags,
+            args,
+            heredocs,
+            span: Span {
+                start_line,
+                end_line,
+            },
+        });
+    }
+
+    if !nodes.iter().any(|node| node.keyword == "FROM") {
+        return error(lines.len().max(1), "no FROM instruction".to_owned());
+    }
+
+    Ok(Dockerfile {
+        directives,
+        escape,
+        nodes,
+    })
+}
+
+fn is_blank_or_comment(line: &str) -> bool {
+    let line = line.trim_start();
+    line.is_empty() || line.starts_with('#')
+}
+
+/// `# key=value`, for the directives docker knows about.
+fn parse_directive(line: &str) -> Option<(String, String)> {
+    let line = line.trim();
+    if !line.starts_with('#') {
+        return None;
+    }
+    let body = &line[1..];
+    let mut parts = body.splitn(2, '=');
+    let key = parts.next()?.trim().to_lowercase();
+    let value = parts.next()?.trim().to_owned();
+    match key.as_str() {
+        "syntax" | "escape" | "check" => Some((key, value)),
+        _ => None,
+    }
+}
+
+/// Delimiters of `<<EOF`, `<<-EOF`, `<<"EOF"` in the order they appear.
+/// The flag says whether leading tabs are stripped from the body (`<<-`).
+/// A `<<` in quotes, in a comment or in `$((…))`, where it shifts, doesn't count.
+pub(crate) fn heredoc_delimiters(text: &str) -> Vec<(String, bool)> {
+    let bytes = text.as_bytes();
+    let mut found = vec![];
+    let mut quote = None;
+    // Parentheses open in an arithmetic expansion.
+    let mut arithmetic = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match (quote, bytes[i]) {
+            (Some(b'\''), b'\'') => quote = None,
+            (Some(b'\''), _) => {}
+            (_, b'\\') => i += 1,
+            (Some(b'"'), b'"') => quote = None,
+            (Some(_), _) => {}
+            (None, c) if c == b'\'' || c == b'"' => quote = Some(c),
+            (None, b'#') if i == 0 || b" \t\n;&|(".contains(&bytes[i - 1]) => {
+                i = text[i..].find('\n').map_or(bytes.len(), |end| i + end);
+            }
+            (None, b'$') if text[i..].starts_with("$((") => {
+                arithmetic += 2;
+                i += 2;
+            }
+            (None, b'(') if arithmetic > 0 => arithmetic += 1,
+            (None, b')') if arithmetic > 0 => arithmetic -= 1,
+            // `<<<` is a here-string, not a heredoc.
+            (None, b'<') if text[i..].starts_with("<<<") => i += 2,
+            (None, b'<') if text[i..].starts_with("<<") && arithmetic == 0 => {
+                let rest = &text[i + 2..];
+                let strip_tabs = rest.starts_with('-');
+                let word = rest.trim_start_matches('-');
+                let word = word.trim_start_matches(&['"', '\''][..]);
+                let len = word
+                    .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                    .unwrap_or(word.len());
+                if len > 0 {
+                    found.push((word[..len].to_owned(), strip_tabs));
+                }
+                i += 1;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    found
+}
+
+fn json_string(text: &str) -> String {
+    serde_json::to_string(text).unwrap()
+}
@@ -0,0 +1,144 @@
+//! The `edit` built-in: a file from the image, changed in the host's editor
+//! and recorded as a `COPY` from the managed build context.
+
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::process::Command;
+
+use tar::Archive;
+
+use super::context::{private_dir, ManagedContext};
+use super::diff::FileChange;
+use super::error::{Error, Result};
+use super::instruction::Instruction;
+use super::runtime::{ChangeKind, ContainerRuntime};
+use super::scratch::ScratchContainers;
+use super::State;
+
+/// A file as it is in a container.
+struct ContainerFile {
+    contents: Vec<u8>,
+    mode: u32,
+    uid: u64,
+    gid: u64,
+}
+
+/// Lets the user edit `path` (absolute) as it is in the state's image.
+/// Returns the `COPY` that makes the change and the change, or None if nothing changed.
+pub(crate) fn edit(
+    runtime: &dyn ContainerRuntime,
+    scratch: &ScratchContainers,
+    state: &State,
+    managed: &ManagedContext,
+    path: &str,
+) -> Result<Option<(Instruction, FileChange)>> {
+    let name = match Path::new(path).file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => return Err(Error::BadArgument(format!("edit: {} is not a file", path))),
+    };
+    let container = scratch.get(runtime, state)?;
+    let (kind, original) = match read_file(runtime, &container, path) {
+        Ok(file) => (ChangeKind::Modified, file),
+        // A new file, then.
+        Err(Error::FileNotFound(_)) => (
+            ChangeKind::Added,
+            ContainerFile {
+                contents: vec![],
+                mode: 0o644,
+                uid: 0,
+                gid: 0,
+            },
+        ),
+        Err(err) => return Err(err),
+    };
+
+    let edited = in_editor(state.editor.as_deref(), &name, &original.contents)?;
+    if edited == original.contents {
+        return Ok(None);
+    }
+
+    let (source_dir, dir) = managed.new_dir("edit")?;
+    let file = dir.join(&name);
+    fs::write(&file, &edited)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        // COPY gives the file the mode it has in the context.
+        fs::set_permissions(&file, fs::Permissions::from_mode(original.mode & 0o7777))?;
+    }
+
+    let mut flags = vec![];
+    if original.uid != 0 || original.gid != 0 {
+        flags.push(format!("--chown={}:{}", original.uid, original.gid));
+    }
+    let copy = Instruction::Copy {
+        flags,
+        sources: vec![format!("{}/{}", source_dir, name)],
+        dest: path.to_owned(),
+        heredocs: vec![],
+    };
+    let change = FileChange::new(path, kind, Some(edited.len() as u64));
+    Ok(Some((copy, change)))
+}
+
+fn read_file(runtime: &dyn ContainerRuntime, container: &str, path: &str) -> Result<ContainerFile> {
+    let mut archive = Archive::new(runtime.download_path(container, path)?);
+    let mut entries = archive.entries()?;
+    let mut entry = match entries.next() {
+        Some(entry) => entry?,
+        None => return Err(Error::FileNotFound(path.to_owned())),
+    };
+    if !entry.header().entry_type().is_file() {
+        return Err(Error::BadArgument(format!(
+            "edit: {} is not a regular file",
+            path
+        )));
+    }
+    let header = entry.header().clone();
+    let mut contents = vec![];
+    entry.read_to_end(&mut contents)?;
+    Ok(ContainerFile {
+        contents,
+        mode: header.mode()?,
+        uid: header.uid()?,
+        gid: header.gid()?,
+    })
+}
+
+/// Opens a copy of `contents` in `editor`, else `$VISUAL` or `$EDITOR`
+/// (else `vi`), returning what it was saved as.
+fn in_editor(editor: Option<&str>, name: &str, contents: &[u8]) -> Result<Vec<u8>> {
+    let editor = match editor {
+        Some(editor) => editor.to_owned(),
+        None => std::env::var("VISUAL")
+            .or_else(|_| std::env::var("EDITOR"))
+            .unwrap_or_else(|_| "vi".to_owned()),
+    };
+    // Named as in the container so the editor knows what kind of file it is.
+    let dir = private_dir()?;
+    let file = dir.join(name);
+    let edited = fs::write(&file, contents)
+        .and_then(|_| {
+            // Through the shell, as editors are often given with arguments.
+            Command::new("sh")
+                .arg("-c")
+                .arg(format!("{} \"$1\"", editor))
+                .arg("sh")
+                .arg(&file)
+                .status()
+        })
+        .map_err(Error::from)
+        .and_then(|status| {
+            if status.success() {
+                Ok(fs::read(&file)?)
+            } else {
+                Err(Error::BadArgument(format!(
+                    "edit: {} exited with {}",
+                    editor, status
+                )))
+            }
+        });
+    let _ = fs::remove_dir_all(&dir);
+    edited
+}
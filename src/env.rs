@@ -0,0 +1,81 @@
+//! The shell environment, as tracked between commands.
+
+use std::collections::BTreeMap;
+
+use super::Instruction;
+
+/// Variable name to value.
+pub(crate) type Env = BTreeMap<String, String>;
+
+/// Set by the shell or docker per container, not by anything the user ran.
+const IGNORED: &[&str] = &["HOSTNAME", "OLDPWD", "PWD", "SHLVL", "_"];
+
+/// Parses the output of `env`. Lines that don't start a new variable
+/// belong to a multi-line value.
+pub(crate) fn parse(text: &str) -> Env {
+    let mut env = Env::new();
+    let mut last: Option<String> = None;
+    for line in text.lines() {
+        let line = line.trim_end_matches('\r');
+        match line.find('=') {
+            Some(i) if is_name(&line[..i]) => {
+                env.insert(line[..i].to_owned(), line[i + 1..].to_owned());
+                last = Some(line[..i].to_owned());
+            }
+            _ => {
+                if let Some(value) = last.as_ref().and_then(|name| env.get_mut(name)) {
+                    value.push('\n');
+                    value.push_str(line);
+                }
+            }
+        }
+    }
+    env
+}
+
+/// What `ENV` would take `before` to `after`. Docker can't unset a
+/// variable, so removed ones are set to empty instead.
+pub(crate) fn diff(before: &Env, after: &Env) -> Vec<(String, String)> {
+    let ignored = |name: &String| IGNORED.contains(&name.as_str());
+    let mut changes: Vec<(String, String)> = after
+        .iter()
+        .filter(|(name, value)| !ignored(name) && before.get(*name) != Some(value))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    changes.extend(
+        before
+            .iter()
+            .filter(|(name, value)| {
+                !ignored(name) && !value.is_empty() && !after.contains_key(*name)
+            })
+            .map(|(name, _)| (name.clone(), String::new())),
+    );
+    changes
+}
+
+/// `NAME=value` for every variable set by the recipe's `ENV` instructions,
+/// to pass to new containers.
+pub(crate) fn from_instructions(lines: &[Instruction]) -> Vec<String> {
+    let mut env = Env::new();
+    for line in lines {
+        if let Instruction::Env(vars) = line {
+            env.extend(vars.iter().cloned());
+        }
+    }
+    env.into_iter()
+        .map(|(name, value)| format!("{}={}", name, value.replace("\\$", "$")))
+        .collect()
+}
+
+/// Values as they were in the container, for `ENV` to set and not substitute.
+pub(crate) fn escape(vars: Vec<(String, String)>) -> Vec<(String, String)> {
+    vars.into_iter()
+        .map(|(name, value)| (name, value.replace('$', "\\$")))
+        .collect()
+}
+
+fn is_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
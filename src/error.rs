@@ -0,0 +1,119 @@
+use std::fmt;
+use std::io;
+use std::string::FromUtf8Error;
+use std::time::Duration;
+
+use dockworker::errors::{Error as DockworkerError, ErrorKind};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Why a line (or the whole session) failed.
+#[derive(Debug)]
+pub enum Error {
+    /// The docker daemon could not be reached.
+    DaemonConnection(String),
+    ImageMissing(String),
+    ContainerNotFound(String),
+    /// No such file or directory in a container.
+    FileNotFound(String),
+    /// `docker build` of the named image failed.
+    BuildFailed {
+        image: String,
+        /// The step it failed at, e.g. `Step 2/2 : RUN make`, if it got that far.
+        step: String,
+        /// What that step printed.
+        output: String,
+        message: String,
+    },
+    /// The container's shell (or command) could not be executed.
+    CommandNotFound(String),
+    /// A session file could not be read or written.
+    BadSession {
+        path: String,
+        message: String,
+    },
+    /// A built-in was used wrongly, e.g. `undo 99`.
+    BadArgument(String),
+    /// Any other error reported by the daemon.
+    Docker(String),
+    Io(io::Error),
+    /// Command output was not UTF-8.
+    Decode(FromUtf8Error),
+    /// Ctrl-C while a command ran.
+    Interrupted,
+    /// A command ran for longer than this.
+    TimedOut(Duration),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::DaemonConnection(msg) => write!(f, "could not connect to docker: {}", msg),
+            Error::ImageMissing(image) => write!(f, "no such image: {}", image),
+            Error::ContainerNotFound(name) => write!(f, "no such container: {}", name),
+            Error::FileNotFound(msg) => write!(f, "no such file: {}", msg),
+            Error::BuildFailed { step, message, .. } if !step.is_empty() => {
+                write!(f, "{} failed: {}", step, message)
+            }
+            Error::BuildFailed { image, message, .. } => {
+                write!(f, "building {} failed: {}", image, message)
+            }
+            Error::CommandNotFound(msg) => write!(f, "command not found: {}", msg),
+            Error::BadSession { path, message } => {
+                write!(f, "session {}: {}", path, message)
+            }
+            Error::BadArgument(msg) => write!(f, "{}", msg),
+            Error::Docker(msg) => write!(f, "docker: {}", msg),
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Decode(err) => write!(f, "output was not utf-8: {}", err),
+            Error::Interrupted => write!(f, "interrupted, nothing was recorded"),
+            Error::TimedOut(after) => write!(
+                f,
+                "timed out after {}s, nothing was recorded",
+                after.as_secs()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<FromUtf8Error> for Error {
+    fn from(err: FromUtf8Error) -> Self {
+        Error::Decode(err)
+    }
+}
+
+impl From<DockworkerError> for Error {
+    /// Docker only hands back a message for API errors, so sort them by that.
+    fn from(err: DockworkerError) -> Self {
+        let message = err.to_string();
+        match err.kind() {
+            ErrorKind::Docker(_) => {
+                if message.contains("No such image") {
+                    Error::ImageMissing(message)
+                } else if message.contains("No such container") {
+                    Error::ContainerNotFound(message)
+                } else if message.contains("Could not find the file") {
+                    Error::FileNotFound(message)
+                } else if message.contains("executable file not found") {
+                    Error::CommandNotFound(message)
+                } else {
+                    Error::Docker(message)
+                }
+            }
+            ErrorKind::Hyper(_) | ErrorKind::CouldNotConnect(_) => Error::DaemonConnection(message),
+            ErrorKind::Io(io_err) => Error::Io(match io_err.kind() {
+                io::ErrorKind::Other => io::Error::other(message),
+                kind => io::Error::new(kind, message),
+            }),
+            _ => Error::Docker(message),
+        }
+    }
+}
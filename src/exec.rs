@@ -1,146 +1,340 @@
-use std::io::{BufRead, BufReader, Read, Write};
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use dockworker::container::*;
-use dockworker::*;
 use rand::Rng;
-use termion::raw::IntoRawMode;
 
+use super::diff::FileChange;
+use super::env;
+use super::error::Result;
+use super::interrupt::Watch;
+use super::runtime::{Attached, Change, ChangeKind, ContainerRuntime};
+use super::scratch::ScratchContainers;
+use super::tty;
 use super::State;
 
-type Result<T> = std::result::Result<T, ()>;
-
 pub struct ExecResults {
     pub state_change: bool,
-    pub output: String,
+    /// What the command did to the filesystem, by path, if that changed state.
+    pub changes: Vec<FileChange>,
+    /// What the command printed. In tty mode this has its stderr too.
+    pub stdout: String,
+    pub stderr: String,
+    /// None if docker couldn't say, which counts as failing.
+    pub exit_code: Option<i64>,
+    /// How long the command ran for.
+    pub duration: Duration,
     pub container_name: String,
+    /// Whether the container was taken out of scratch, as anything at all
+    /// changed in it, so it is the caller's to commit or remove.
+    pub taken: bool,
+    /// Variables the command set, changed or unset, as for `ENV`.
+    pub env_changes: Vec<(String, String)>,
+    /// Where the shell was left, if the command got as far as the end.
+    pub dirs: Option<Dirs>,
 }
 
-/// Executes the last command of the state.
-/// Ok means the command was executed. Err means that docker couldn't find the command...
-pub(crate) fn execute_command(docker: &Docker, state: &State) -> Result<ExecResults> {
-    if state.debug {
-        println!("do_line: {:?}", &state);
+impl ExecResults {
+    /// Everything the command printed.
+    pub fn output(&self) -> String {
+        format!("{}{}", self.stdout, self.stderr)
     }
 
-    let container_name: String = rand::thread_rng().gen_range(0., 1.3e4).to_string();
-    assert_eq!(state.lines[0][0], "FROM");
+    pub fn succeeded(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+}
 
-    let mut host_config = ContainerHostConfig::new();
-    host_config.auto_remove(false);
+/// Where a shell is: its working directory, where `cd -` goes and the `pushd` stack.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Dirs {
+    pub pwd: String,
+    pub oldpwd: String,
+    /// Most recently pushed first.
+    pub stack: Vec<String>,
+}
 
-    let mut create = ContainerCreateOptions::new(&state.image_name);
-    create.tty(state.tty);
+/// Holds the environment from before the command.
+const ENV_VAR: &str = "__dockershell_env";
+/// Holds the command's exit status while the trailer runs.
+const STATUS_VAR: &str = "__dockershell_status";
+/// The `pushd` stack, one directory per line.
+const DIRS_VAR: &str = "__dockershell_dirs";
 
-    let mut args = state.lines.last().unwrap().clone();
-    args.remove(0); //assert [0] == RUN
-    if state.debug {
-        println!("running cmd: {:?}", &args);
-    }
+/// `pushd` and `popd`, which `sh` doesn't have, keeping their stack in `DIRS_VAR`.
+const DIR_FUNCTIONS: &str = r#"pushd() {
+    if [ $# -ne 1 ]; then
+        echo "usage: pushd dir" >&2
+        return 2
+    fi
+    cd "$1" || return
+    __dockershell_dirs="$OLDPWD
+$__dockershell_dirs"
+}
+popd() {
+    if [ -z "$__dockershell_dirs" ]; then
+        echo "popd: directory stack empty" >&2
+        return 1
+    fi
+    cd "${__dockershell_dirs%%
+*}" || return
+    __dockershell_dirs="${__dockershell_dirs#*
+}"
+}"#;
 
-    create.cmd(state.shell.clone());
-    create.cmd("-c".to_owned());
-    create.cmd(args.join(" "));
-
-    create.host_config(host_config);
-
-    let container = docker
-        .create_container(Some(&container_name), &create)
-        .unwrap();
-    let mut results = Vec::<String>::new();
-
-    if state.tty {
-        let res = docker
-            .attach_container(&container.id, None, true, true, true, true, true)
-            .unwrap().unwrap();
-        let result = docker.start_container(&container.id);
-
-        match result {
-            Ok(_) => {
-                if state.debug {
-                    println!(
-                        "starting container id  {} with name  {} ",
-                        container.id, &container_name
-                    );
-                }
-
-                let mut raw_stdout = std::io::stdout().into_raw_mode().unwrap();
-                let mut line_reader = BufReader::new(res);
-
-                loop {
-                    let mut buf = String::new(); // [0u8;50];
-                    let size_result = line_reader.read_line(&mut buf);
-                    if let Ok(size) = size_result {
-                        raw_stdout.write_all(buf.as_bytes()).unwrap();
-                        if size == 0 {
-                            break;
-                        }
-                        results.push(buf);
-                    }
-                }
-            }
-            Err(err) => {
-                println!("{:?}", err);
-                return Err(());
-            }
-        }
-    } else {
-        // non-tty mode kept for tests for now....
-        let res = docker
-            .attach_container(&container.id, None, true, true, true, true, true)
-            .unwrap();
-        let result = docker.start_container(&container.id);
-
-        match result {
-            Ok(_) => {
-                if state.debug {
-                    println!(
-                        "starting container id  {} with name  {} ",
-                        container.id, &container_name
-                    );
-                }
-
-                let mut cont: AttachContainer = res.into();
-                let mut buf = vec![];
-                // These streams are split out in non-tty mode:
-                cont.stdout.read_to_end(&mut buf).unwrap();
-                cont.stderr.read_to_end(&mut buf).unwrap();
-                results.push(String::from_utf8(buf).unwrap());
-            }
-            Err(err) => {
-                println!("{:?}", err);
-                return Err(());
-            }
+/// Random name for a container or image.
+pub(crate) fn unique_name() -> String {
+    rand::thread_rng().gen_range(0., 1.3e4).to_string()
+}
+
+/// Wraps the command so we see where it left the shell, keeping its exit
+/// status. After its output come, each after a `marker` line: the working
+/// directory, `$OLDPWD`, the `pushd` stack, the environment before and the
+/// environment after.
+fn script(command: &str, marker: &str, state: &State) -> String {
+    let stack: String = state
+        .dir_stack
+        .iter()
+        .map(|dir| format!("{}\n", dir))
+        .collect();
+    format!(
+        "{env}=$(env)\n{}\nOLDPWD={}\n{dirs}={}\n{}\n{status}=$?\n\
+         printf '\\n%s\\n%s' {marker} \"$(pwd)\" {marker} \"$OLDPWD\" {marker} \"${dirs}\" \
+         {marker} \"${env}\" {marker}\n\
+         env\nexit ${status}",
+        DIR_FUNCTIONS,
+        sh_quote(&state.oldpwd),
+        sh_quote(&stack),
+        command,
+        env = ENV_VAR,
+        dirs = DIRS_VAR,
+        status = STATUS_VAR,
+        marker = marker
+    )
+}
+
+/// What the fake runtime needs to know of a `script`.
+pub(crate) struct ScriptParts<'a> {
+    pub command: &'a str,
+    pub marker: &'a str,
+    pub oldpwd: String,
+    pub stack: Vec<String>,
+}
+
+/// The inverse of `script`.
+pub(crate) fn split_script(script: &str) -> Option<ScriptParts<'_>> {
+    let start = script.find(DIR_FUNCTIONS)? + DIR_FUNCTIONS.len() + 1;
+    let (oldpwd, rest) = take_assignment(script.get(start..)?, "OLDPWD")?;
+    let (stack, rest) = take_assignment(rest, DIRS_VAR)?;
+    let end = rest.rfind(&format!("\n{}=$?\n", STATUS_VAR))?;
+    let marker = rest[end..].lines().nth(2)?.split_whitespace().nth(2)?;
+    Some(ScriptParts {
+        command: &rest[..end],
+        marker,
+        oldpwd,
+        stack: stack.lines().map(|dir| dir.to_owned()).collect(),
+    })
+}
+
+/// Single quotes `value` for the shell.
+fn sh_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Reads back a `name=<sh_quote'd value>` line from the start of `text`.
+fn take_assignment<'a>(text: &'a str, name: &str) -> Option<(String, &'a str)> {
+    let prefix = format!("{}='", name);
+    if !text.starts_with(&prefix) {
+        return None;
+    }
+    let mut rest = &text[prefix.len()..];
+    let mut value = String::new();
+    loop {
+        let end = rest.find('\'')?;
+        value.push_str(&rest[..end]);
+        rest = &rest[end + 1..];
+        if rest.starts_with("\\''") {
+            value.push('\'');
+            rest = &rest[3..];
+        } else {
+            break;
         }
     }
+    if rest.starts_with('\n') {
+        rest = &rest[1..];
+    }
+    Some((value, rest))
+}
 
-    let search_name = String::from("/") + &container_name;
-    let mut filters = ContainerFilters::new();
-    filters.name(&search_name);
+/// Executes a shell command on top of the state's image, in that image's scratch container.
+/// Ok means the command was executed (whatever its outcome). Err says why it couldn't be,
+/// or that it was stopped by Ctrl-C or `state.timeout`, in which case its container is gone.
+///
+/// If the command changed the filesystem at all, even just in ignored places,
+/// the container has been taken out of `scratch` and is the caller's to
+/// commit or remove.
+pub(crate) fn execute_command(
+    runtime: &dyn ContainerRuntime,
+    scratch: &ScratchContainers,
+    state: &State,
+    command: &str,
+) -> Result<ExecResults> {
+    if state.debug {
+        println!("do_line: {:?}", &state);
+    }
 
-    let res = docker.list_containers(None, None, None, filters).unwrap();
-    let container: &Container = res.first().unwrap();
+    let container_name = scratch.get(runtime, state)?;
+
+    if state.debug {
+        println!("running cmd: {:?} in {}", command, container_name);
+    }
 
-    let changes = docker.filesystem_changes(&container);
-    let state_change = match changes {
-        Ok(some) => {
-            if state.debug {
-                println!("CHANGES: {:?}", some);
+    let marker = format!("--dockershell-{}--", rand::random::<u64>());
+    let script = script(command, &marker, state);
+    let cmd = vec![state.shell.clone(), "-c".to_owned(), script];
+    let started = Instant::now();
+    let watch = Watch::new(state.timeout);
+    let read_result = runtime
+        .create_exec(&container_name, &cmd, state.tty)
+        .and_then(|exec_id| {
+            let output = match runtime.start_exec(&exec_id, state.tty)? {
+                Attached::Tty { output, input } => (
+                    tty::pump(runtime, &exec_id, output, input, &marker, &watch)?,
+                    String::new(),
+                ),
+                // non-tty mode kept for tests for now....
+                Attached::Split { stdout, stderr } => read_split(stdout, stderr, &watch)?,
             };
-            true
-        }
-        Err(_none) => {
-            if state.debug { /*println!("CHANGES: {:?}", none);*/ };
-            false
+            Ok((output, exit_code(runtime, &exec_id)?))
+        });
+    let duration = started.elapsed();
+    let output = read_result.and_then(|output| {
+        let changes = runtime.filesystem_changes(&container_name)?;
+        Ok((output, changes))
+    });
+
+    let (((stdout, stderr), exit_code), changes) = match output {
+        Ok(output) => output,
+        Err(err) => {
+            // Can't tell what state it is in now.
+            if scratch.take(state).is_some() {
+                let _ = runtime.remove_container(&container_name);
+            }
+            return Err(err);
         }
     };
+    if state.debug && !changes.is_empty() {
+        println!("CHANGES: {:?}", changes);
+    }
+    // E.g. just the shell's history file, or a package manager's cache.
+    let state_change = state.ignore.any_significant(&changes);
+    // Not the same as its image anymore either way.
+    let taken = !changes.is_empty() && scratch.take(state).is_some();
+    let changes = if state_change {
+        with_sizes(runtime, &container_name, state.ignore.unignored(&changes))
+    } else {
+        vec![]
+    };
 
-    docker
-        .remove_container(&container_name, None, Some(true), None)
-        .unwrap();
-
-    Ok(ExecResults {
+    let sections: Vec<&str> = stdout.splitn(6, &format!("\n{}\n", marker)).collect();
+    let mut results = ExecResults {
         state_change,
-        output: results.join("\n"),
+        changes,
+        stdout: sections[0].to_owned(),
+        stderr,
+        exit_code,
+        duration,
         container_name,
-    })
+        taken,
+        env_changes: vec![],
+        dirs: None,
+    };
+    // If the command exited the shell early there is no trailer, and no way to tell.
+    if let [_, pwd, oldpwd, stack, before, after] = sections[..] {
+        results.env_changes = env::diff(&env::parse(before), &env::parse(after));
+        results.dirs = Some(Dirs {
+            pwd: pwd.to_owned(),
+            oldpwd: oldpwd.to_owned(),
+            stack: stack.lines().map(|dir| dir.to_owned()).collect(),
+        });
+    }
+    Ok(results)
+}
+
+/// Sorted by path. Sizes are only nice to have, so left out if they can't be had.
+fn with_sizes(
+    runtime: &dyn ContainerRuntime,
+    container: &str,
+    changes: Vec<Change>,
+) -> Vec<FileChange> {
+    let paths: Vec<String> = changes
+        .iter()
+        .filter(|change| change.kind != ChangeKind::Deleted)
+        .map(|change| change.path.clone())
+        .collect();
+    let sizes = match runtime.file_sizes(container, &paths) {
+        Ok(sizes) => paths.into_iter().zip(sizes).collect(),
+        Err(_) => HashMap::new(),
+    };
+    let mut changes: Vec<FileChange> = changes
+        .into_iter()
+        .map(|change| {
+            let size = sizes.get(&change.path).cloned().unwrap_or(None);
+            FileChange::new(&change.path, change.kind, size)
+        })
+        .collect();
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+    changes
+}
+
+/// How often to see whether a command should be given up on.
+const WATCH_MS: u64 = 50;
+
+/// How long docker may take to say how an exec exited once its output has ended.
+const EXIT_WAIT: Duration = Duration::from_secs(5);
+const EXIT_POLL_MS: u64 = 10;
+
+/// The exec's exit code. Docker may still have it running just after its
+/// output ends, so this asks again until it has exited, or gives up with None.
+fn exit_code(runtime: &dyn ContainerRuntime, exec_id: &str) -> Result<Option<i64>> {
+    let started = Instant::now();
+    loop {
+        let code = runtime.exec_exit_code(exec_id)?;
+        if code.is_some() || started.elapsed() >= EXIT_WAIT {
+            return Ok(code);
+        }
+        thread::sleep(Duration::from_millis(EXIT_POLL_MS));
+    }
+}
+
+fn read_split(
+    mut stdout: Box<dyn Read + Send>,
+    mut stderr: Box<dyn Read + Send>,
+    watch: &Watch,
+) -> Result<(String, String)> {
+    // Read elsewhere, as reading blocks until the command is done.
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        // These streams are split out in non-tty mode:
+        let mut out = vec![];
+        let mut err = vec![];
+        let read = stdout
+            .read_to_end(&mut out)
+            .and_then(|_| stderr.read_to_end(&mut err))
+            .map(|_| (out, err));
+        // Nobody is listening if it was given up on.
+        let _ = sender.send(read);
+    });
+    loop {
+        match receiver.recv_timeout(Duration::from_millis(WATCH_MS)) {
+            Ok(read) => {
+                let (out, err) = read?;
+                return Ok((String::from_utf8(out)?, String::from_utf8(err)?));
+            }
+            Err(RecvTimeoutError::Timeout) => watch.check()?,
+            Err(RecvTimeoutError::Disconnected) => panic!("output thread panicked"),
+        }
+    }
 }
@@ -0,0 +1,647 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde_json::json;
+use tar::{Archive, Builder, Header};
+
+use super::error::{Error, Result};
+use super::exec::split_script;
+use super::runtime::{
+    Attached, Change, ChangeKind, Commit, Committing, ContainerRuntime, ContainerSpec, ImageInfo,
+};
+
+/// Docker's `PATH` for containers whose image doesn't set one.
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// What a scripted command prints and does to the filesystem.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FakeCommand {
+    pub stdout: String,
+    pub stderr: String,
+    pub changes: Vec<Change>,
+    /// Variables exported (`Some`) or unset (`None`), in order.
+    pub env: Vec<(String, Option<String>)>,
+    /// Where it leaves the shell, if it changes directory.
+    pub cd: Option<String>,
+    pub exit_code: i64,
+    /// How long before its output comes.
+    pub runs_for: Duration,
+    /// How many times its exec is said to be running after its output has ended.
+    pub exits_after: usize,
+}
+
+impl FakeCommand {
+    pub fn output(stdout: &str) -> FakeCommand {
+        FakeCommand {
+            stdout: stdout.to_owned(),
+            ..FakeCommand::default()
+        }
+    }
+
+    /// Adds `path` and does nothing else.
+    pub fn adds(path: &str) -> FakeCommand {
+        FakeCommand::default().changes(vec![Change {
+            path: path.to_owned(),
+            kind: ChangeKind::Added,
+        }])
+    }
+
+    pub fn changes(mut self, changes: Vec<Change>) -> FakeCommand {
+        self.changes = changes;
+        self
+    }
+
+    pub fn export(mut self, name: &str, value: &str) -> FakeCommand {
+        self.env.push((name.to_owned(), Some(value.to_owned())));
+        self
+    }
+
+    pub fn unset(mut self, name: &str) -> FakeCommand {
+        self.env.push((name.to_owned(), None));
+        self
+    }
+
+    pub fn cd(mut self, dir: &str) -> FakeCommand {
+        self.cd = Some(dir.to_owned());
+        self
+    }
+
+    pub fn exit(mut self, code: i64) -> FakeCommand {
+        self.exit_code = code;
+        self
+    }
+
+    pub fn runs_for(mut self, duration: Duration) -> FakeCommand {
+        self.runs_for = duration;
+        self
+    }
+
+    /// Its exit code is only known once asked for `polls` times.
+    pub fn exits_after(mut self, polls: usize) -> FakeCommand {
+        self.exits_after = polls;
+        self
+    }
+}
+
+/// Output that takes a while to start.
+struct Slow<R> {
+    delay: Option<Duration>,
+    inner: R,
+}
+
+impl<R: Read> Read for Slow<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(delay) = self.delay.take() {
+            thread::sleep(delay);
+        }
+        self.inner.read(buf)
+    }
+}
+
+#[derive(Debug)]
+struct FakeContainer {
+    spec: ContainerSpec,
+    /// Its image's working directory.
+    workdir: String,
+    started: bool,
+    /// Everything run in it so far has done this to its filesystem.
+    changes: Vec<Change>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    replies: HashMap<String, FakeCommand>,
+    /// Image name to its working directory.
+    images: HashMap<String, String>,
+    /// Path to contents of the files every container has.
+    files: HashMap<String, Vec<u8>>,
+    /// Path to the tar downloading it gives, whatever the files.
+    archives: HashMap<String, Vec<u8>>,
+    containers: HashMap<String, FakeContainer>,
+    created: usize,
+    /// Exec id to (container, command).
+    execs: HashMap<String, (String, String)>,
+    /// Exec id to exit code, once started.
+    exit_codes: HashMap<String, i64>,
+    /// Exec id to how many more times it is said to be running.
+    still_running: HashMap<String, usize>,
+    commands: Vec<String>,
+    runs: Vec<(String, String)>,
+    builds: Vec<(String, String)>,
+    contexts: Vec<PathBuf>,
+    /// Path in the context to contents, of every file built with but the Dockerfile.
+    built_files: Vec<(String, Vec<u8>)>,
+    commits: Vec<Commit>,
+    /// How long each build's stream takes to end.
+    build_time: Duration,
+    /// How long each commit takes.
+    commit_time: Duration,
+    /// Dockerfile instructions that fail to build, to what they print
+    /// and docker's error.
+    build_failures: HashMap<String, (String, String)>,
+}
+
+/// In-memory stand-in for docker so the interpreter can be driven offline.
+///
+/// Commands are matched on the string handed to the shell (the last element
+/// of `ContainerSpec::cmd`, or of an exec's command without the trailer that
+/// reports the environment). Unscripted commands print nothing and change nothing.
+#[derive(Debug, Default)]
+pub struct FakeRuntime {
+    /// Shared with the commits under way.
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl FakeRuntime {
+    /// A runtime that knows about `alpine:edge` only.
+    pub fn new() -> FakeRuntime {
+        FakeRuntime::default().image("alpine:edge")
+    }
+
+    pub fn image(self, name: &str) -> FakeRuntime {
+        self.inner
+            .lock()
+            .unwrap()
+            .images
+            .insert(name.to_owned(), "/".to_owned());
+        self
+    }
+
+    /// Puts a file in every container. Directories are implied by the files in them.
+    pub fn file(self, path: &str, contents: &str) -> FakeRuntime {
+        self.inner
+            .lock()
+            .unwrap()
+            .files
+            .insert(path.to_owned(), contents.as_bytes().to_vec());
+        self
+    }
+
+    /// Downloading `path` gives `tar` as it is, e.g. to see that
+    /// nothing in it can reach out of where it is unpacked.
+    pub fn archive(self, path: &str, tar: Vec<u8>) -> FakeRuntime {
+        self.inner
+            .lock()
+            .unwrap()
+            .archives
+            .insert(path.to_owned(), tar);
+        self
+    }
+
+    pub fn builds_take(self, duration: Duration) -> FakeRuntime {
+        self.inner.lock().unwrap().build_time = duration;
+        self
+    }
+
+    pub fn commits_take(self, duration: Duration) -> FakeRuntime {
+        self.inner.lock().unwrap().commit_time = duration;
+        self
+    }
+
+    /// Builds stop at `instruction`, which prints `output`, with docker's error `message`.
+    pub fn build_fails(self, instruction: &str, output: &str, message: &str) -> FakeRuntime {
+        self.inner.lock().unwrap().build_failures.insert(
+            instruction.to_owned(),
+            (output.to_owned(), message.to_owned()),
+        );
+        self
+    }
+
+    pub fn on(self, command: &str, reply: FakeCommand) -> FakeRuntime {
+        self.inner
+            .lock()
+            .unwrap()
+            .replies
+            .insert(command.to_owned(), reply);
+        self
+    }
+
+    /// Shell command lines exec'd so far, in order.
+    pub fn commands(&self) -> Vec<String> {
+        self.inner.lock().unwrap().commands.clone()
+    }
+
+    /// (image, shell command line) of every exec so far, in order.
+    pub fn runs(&self) -> Vec<(String, String)> {
+        self.inner.lock().unwrap().runs.clone()
+    }
+
+    /// (tag, Dockerfile) of every build so far, in order.
+    pub fn builds(&self) -> Vec<(String, String)> {
+        self.inner.lock().unwrap().builds.clone()
+    }
+
+    /// Where the tar'd context of every build so far was.
+    pub fn build_contexts(&self) -> Vec<PathBuf> {
+        self.inner.lock().unwrap().contexts.clone()
+    }
+
+    /// (path in the context, contents) of every file built with besides the Dockerfile.
+    pub fn built_files(&self) -> Vec<(String, Vec<u8>)> {
+        self.inner.lock().unwrap().built_files.clone()
+    }
+
+    /// Every commit so far, in order.
+    pub fn commits(&self) -> Vec<Commit> {
+        self.inner.lock().unwrap().commits.clone()
+    }
+
+    /// Containers created and not yet removed.
+    pub fn containers(&self) -> usize {
+        self.inner.lock().unwrap().containers.len()
+    }
+
+    /// Containers created, removed or not.
+    pub fn containers_created(&self) -> usize {
+        self.inner.lock().unwrap().created
+    }
+
+    fn reply(&self, name: &str) -> Result<FakeCommand> {
+        let inner = self.inner.lock().unwrap();
+        let container = inner.containers.get(name).ok_or_else(|| not_found(name))?;
+        let command = container.spec.cmd.last().cloned().unwrap_or_default();
+        Ok(inner.replies.get(&command).cloned().unwrap_or_default())
+    }
+}
+
+impl ContainerRuntime for FakeRuntime {
+    fn create_container(&self, name: &str, spec: &ContainerSpec) -> Result<String> {
+        let mut inner = self.inner.lock().unwrap();
+        let workdir = match inner.images.get(&spec.image) {
+            Some(workdir) => workdir.clone(),
+            None => return Err(Error::ImageMissing(spec.image.clone())),
+        };
+        if inner.containers.contains_key(name) {
+            return Err(Error::Docker(format!(
+                "Conflict. The container name {} is already in use",
+                name
+            )));
+        }
+        inner.created += 1;
+        inner.containers.insert(
+            name.to_owned(),
+            FakeContainer {
+                spec: spec.clone(),
+                workdir,
+                started: false,
+                changes: vec![],
+            },
+        );
+        Ok(name.to_owned())
+    }
+
+    fn attach_container(&self, name: &str, tty: bool) -> Result<Attached> {
+        Ok(attached(self.reply(name)?, tty))
+    }
+
+    fn start_container(&self, name: &str) -> Result<()> {
+        let reply = self.reply(name)?;
+        let mut inner = self.inner.lock().unwrap();
+        let container = inner.containers.get_mut(name).unwrap();
+        container.started = true;
+        container.changes.extend(reply.changes);
+        Ok(())
+    }
+
+    fn create_exec(&self, container: &str, cmd: &[String], _tty: bool) -> Result<String> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.containers.get(container) {
+            None => return Err(not_found(container)),
+            Some(c) if !c.started => {
+                return Err(Error::Docker(format!(
+                    "Container {} is not running",
+                    container
+                )))
+            }
+            Some(_) => {}
+        }
+        let id = format!("exec-{}", inner.execs.len());
+        let command = cmd.last().cloned().unwrap_or_default();
+        inner
+            .execs
+            .insert(id.clone(), (container.to_owned(), command));
+        Ok(id)
+    }
+
+    fn start_exec(&self, id: &str, tty: bool) -> Result<Attached> {
+        let mut inner = self.inner.lock().unwrap();
+        let (name, command) = inner
+            .execs
+            .get(id)
+            .cloned()
+            .ok_or_else(|| Error::Docker(format!("No such exec instance: {}", id)))?;
+        let (command, parts) = match split_script(&command) {
+            Some(parts) => (parts.command.to_owned(), Some(parts)),
+            None => (command.clone(), None),
+        };
+        let mut reply = inner.replies.get(&command).cloned().unwrap_or_default();
+        let (image, workdir, env) = {
+            let container = inner
+                .containers
+                .get_mut(&name)
+                .ok_or_else(|| not_found(&name))?;
+            container.changes.extend(reply.changes.clone());
+            let mut env = container.spec.env.clone();
+            env.push(format!("HOSTNAME={}", name));
+            (container.spec.image.clone(), container.workdir.clone(), env)
+        };
+        inner.commands.push(command.clone());
+        inner.runs.push((image, command));
+        inner.exit_codes.insert(id.to_owned(), reply.exit_code);
+        inner.still_running.insert(id.to_owned(), reply.exits_after);
+
+        if let Some(parts) = parts {
+            if tty {
+                // Keep the trailer last, as a real tty would.
+                let stderr = std::mem::take(&mut reply.stderr);
+                reply.stdout.push_str(&stderr);
+            }
+            let (pwd, oldpwd) = match &reply.cd {
+                Some(dir) => (dir.clone(), workdir),
+                None => (workdir, parts.oldpwd),
+            };
+            let stack: String = parts.stack.iter().map(|dir| format!("{}\n", dir)).collect();
+            let after = reply
+                .env
+                .iter()
+                .fold(env.clone(), |mut env, (name, value)| {
+                    let prefix = format!("{}=", name);
+                    env.retain(|var| !var.starts_with(&prefix));
+                    if let Some(value) = value {
+                        env.push(format!("{}{}", prefix, value));
+                    }
+                    env
+                });
+            for section in &[pwd, oldpwd, stack, env.join("\n")] {
+                reply.stdout += &format!("\n{}\n{}", parts.marker, section);
+            }
+            reply.stdout += &format!("\n{}\n{}\n", parts.marker, after.join("\n"));
+        }
+        Ok(attached(reply, tty))
+    }
+
+    fn exec_exit_code(&self, id: &str) -> Result<Option<i64>> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.still_running.get_mut(id) {
+            Some(polls) if *polls > 0 => {
+                *polls -= 1;
+                Ok(None)
+            }
+            _ => Ok(inner.exit_codes.get(id).cloned()),
+        }
+    }
+
+    fn resize_exec(&self, id: &str, _rows: u16, _cols: u16) -> Result<()> {
+        if self.inner.lock().unwrap().exit_codes.contains_key(id) {
+            Ok(())
+        } else {
+            Err(Error::Docker(format!("No such exec instance: {}", id)))
+        }
+    }
+
+    fn filesystem_changes(&self, name: &str) -> Result<Vec<Change>> {
+        let inner = self.inner.lock().unwrap();
+        let container = inner.containers.get(name).ok_or_else(|| not_found(name))?;
+        Ok(container.changes.clone())
+    }
+
+    /// Sizes of the files given with `file`.
+    fn file_sizes(&self, container: &str, paths: &[String]) -> Result<Vec<Option<u64>>> {
+        let inner = self.inner.lock().unwrap();
+        if !inner.containers.contains_key(container) {
+            return Err(not_found(container));
+        }
+        Ok(paths
+            .iter()
+            .map(|path| inner.files.get(path).map(|contents| contents.len() as u64))
+            .collect())
+    }
+
+    /// What is under the directory among the files given with `file`.
+    fn list_dir(&self, container: &str, dir: &str) -> Result<Vec<String>> {
+        let inner = self.inner.lock().unwrap();
+        if !inner.containers.contains_key(container) {
+            return Err(not_found(container));
+        }
+        let inside = format!("{}/", dir.trim_end_matches('/'));
+        let mut names: Vec<String> = inner
+            .files
+            .keys()
+            .filter(|file| file.starts_with(&inside))
+            .map(|file| match file[inside.len()..].find('/') {
+                Some(end) => file[inside.len()..inside.len() + end + 1].to_owned(),
+                None => file[inside.len()..].to_owned(),
+            })
+            .collect();
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    /// The files given with `file` that are in a directory on the container's
+    /// `PATH`, or on docker's default one.
+    fn executables(&self, container: &str) -> Result<Vec<String>> {
+        let inner = self.inner.lock().unwrap();
+        let container = inner
+            .containers
+            .get(container)
+            .ok_or_else(|| not_found(container))?;
+        let path = container
+            .spec
+            .env
+            .iter()
+            .find(|var| var.starts_with("PATH="))
+            .map(|var| &var["PATH=".len()..])
+            .unwrap_or(DEFAULT_PATH);
+        let mut names: Vec<String> = inner
+            .files
+            .keys()
+            .filter_map(|file| {
+                let file = Path::new(file);
+                let dir = file.parent()?.to_str()?;
+                if path.split(':').any(|on_path| on_path == dir) {
+                    Some(file.file_name()?.to_string_lossy().into_owned())
+                } else {
+                    None
+                }
+            })
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    fn remove_container(&self, name: &str) -> Result<()> {
+        self.inner
+            .lock()
+            .unwrap()
+            .containers
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| not_found(name))
+    }
+
+    fn download_path(&self, container: &str, path: &str) -> Result<Box<dyn Read>> {
+        let inner = self.inner.lock().unwrap();
+        if !inner.containers.contains_key(container) {
+            return Err(not_found(container));
+        }
+        if let Some(tar) = inner.archives.get(path) {
+            return Ok(Box::new(Cursor::new(tar.clone())));
+        }
+        let path = Path::new(path);
+        // A file, or the files under a directory.
+        let mut found: Vec<(&String, &Vec<u8>)> = inner
+            .files
+            .iter()
+            .filter(|(file, _)| Path::new(file).starts_with(path))
+            .collect();
+        if found.is_empty() {
+            return Err(Error::FileNotFound(format!(
+                "Could not find the file {} in container",
+                path.display()
+            )));
+        }
+        found.sort();
+        let top = Path::new(path.file_name().unwrap());
+        let mut tar = Builder::new(vec![]);
+        for (file, contents) in found {
+            let name = match Path::new(file).strip_prefix(path).unwrap() {
+                rest if rest.as_os_str().is_empty() => top.to_owned(),
+                rest => top.join(rest),
+            };
+            let mut header = Header::new_gnu();
+            header.set_path(&name)?;
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            header.set_cksum();
+            tar.append(&header, &contents[..])?;
+        }
+        Ok(Box::new(Cursor::new(tar.into_inner()?)))
+    }
+
+    fn build_image(&self, tag: &str, context: &Path) -> Result<Box<dyn Read + Send>> {
+        let mut dockerfile = String::new();
+        let mut files = vec![];
+        let mut archive = Archive::new(File::open(context)?);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_string_lossy().into_owned();
+            if path == "Dockerfile" {
+                entry.read_to_string(&mut dockerfile)?;
+            } else if entry.header().entry_type().is_file() {
+                let mut contents = vec![];
+                entry.read_to_end(&mut contents)?;
+                files.push((path, contents));
+            }
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.built_files.extend(files);
+        // Left as it was in the image built on unless set.
+        let from = dockerfile
+            .lines()
+            .find_map(|line| line.strip_prefix("FROM "))
+            .and_then(|image| inner.images.get(image.trim()))
+            .map_or("/", |workdir| workdir.as_str());
+        let workdir = dockerfile
+            .lines()
+            .rev()
+            .find(|line| line.starts_with("WORKDIR "))
+            .map_or(from, |line| line["WORKDIR ".len()..].trim())
+            .to_owned();
+        inner.contexts.push(context.to_owned());
+        let instructions: Vec<&str> = dockerfile.lines().collect();
+        let mut stream = String::new();
+        let mut failed = false;
+        for (step, instruction) in instructions.iter().enumerate() {
+            let message = format!(
+                "Step {}/{} : {}\n",
+                step + 1,
+                instructions.len(),
+                instruction
+            );
+            stream += &format!("{}\n", json!({ "stream": message }));
+            if let Some((output, message)) = inner.build_failures.get(*instruction) {
+                stream += &format!("{}\n", json!({ "stream": output }));
+                let error = json!({ "errorDetail": { "message": message }, "error": message });
+                stream += &format!("{}\n", error);
+                failed = true;
+                break;
+            }
+        }
+        if !failed {
+            inner.images.insert(tag.to_owned(), workdir);
+            stream += &format!("{{\"stream\":\"Successfully tagged {}\\n\"}}\n", tag);
+        }
+        inner.builds.push((tag.to_owned(), dockerfile));
+        Ok(Box::new(Slow {
+            delay: Some(inner.build_time),
+            inner: Cursor::new(stream),
+        }))
+    }
+
+    fn commit_container(&self, name: &str, commit: &Commit) -> Result<Committing> {
+        let workdir = match self.inner.lock().unwrap().containers.get(name) {
+            Some(container) => container.workdir.clone(),
+            None => return Err(not_found(name)),
+        };
+        let workdir = commit
+            .changes
+            .iter()
+            .rev()
+            .find(|change| change.starts_with("WORKDIR "))
+            .map_or(workdir, |change| change["WORKDIR ".len()..].to_owned());
+        let (inner, name, commit) = (self.inner.clone(), name.to_owned(), commit.clone());
+        Ok(Box::new(move || {
+            let delay = inner.lock().unwrap().commit_time;
+            thread::sleep(delay);
+            let mut inner = inner.lock().unwrap();
+            inner.images.insert(commit.tag.clone(), workdir);
+            if commit.remove {
+                inner.containers.remove(&name);
+            }
+            inner.commits.push(commit.clone());
+            Ok(commit.tag)
+        }))
+    }
+
+    fn inspect_image(&self, name: &str) -> Result<ImageInfo> {
+        if self.inner.lock().unwrap().images.contains_key(name) {
+            Ok(ImageInfo {
+                id: name.to_owned(),
+            })
+        } else {
+            Err(Error::ImageMissing(name.to_owned()))
+        }
+    }
+}
+
+fn attached(reply: FakeCommand, tty: bool) -> Attached {
+    let slow = |output: String| Slow {
+        delay: Some(reply.runs_for),
+        inner: Cursor::new(output),
+    };
+    if tty {
+        Attached::Tty {
+            output: Box::new(slow(reply.stdout.clone() + &reply.stderr)),
+            input: Box::new(io::sink()),
+        }
+    } else {
+        Attached::Split {
+            stdout: Box::new(slow(reply.stdout.clone())),
+            stderr: Box::new(Cursor::new(reply.stderr.clone())),
+        }
+    }
+}
+
+fn not_found(name: &str) -> Error {
+    Error::ContainerNotFound(name.to_owned())
+}
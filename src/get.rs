@@ -0,0 +1,102 @@
+//! The `get` built-in: files copied out of the current image, recording nothing.
+
+use std::ffi::OsString;
+use std::fs;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+use tar::{Archive, EntryType};
+
+use super::error::{Error, Result};
+use super::runtime::ContainerRuntime;
+use super::scratch::ScratchContainers;
+use super::State;
+
+/// Copies the file or directory at `path` (absolute) in the state's image to `to`
+/// on the host, or into `to` if that is a directory, as `docker cp` does.
+/// What is written belongs to whoever runs dockershell. Returns where it went.
+pub(crate) fn get(
+    runtime: &dyn ContainerRuntime,
+    scratch: &ScratchContainers,
+    state: &State,
+    path: &str,
+    to: &Path,
+) -> Result<PathBuf> {
+    let container = scratch.get(runtime, state)?;
+    let mut archive = Archive::new(runtime.download_path(&container, path)?);
+    // Unpacked next to where it goes, so that it can be moved there whole.
+    let beside = if to.is_dir() {
+        to
+    } else {
+        match to.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        }
+    };
+    fs::create_dir_all(beside)?;
+    let staging = beside.join(format!(".dockershell-get-{}", rand::random::<u32>()));
+    fs::create_dir(&staging)?;
+    let got = unpack(&mut archive, &staging).and_then(|top| {
+        let top = top.ok_or_else(|| Error::FileNotFound(path.to_owned()))?;
+        let dest = if to.is_dir() {
+            to.join(&top)
+        } else {
+            to.to_owned()
+        };
+        move_to(&staging.join(&top), &dest)?;
+        Ok(dest)
+    });
+    let _ = fs::remove_dir_all(&staging);
+    got
+}
+
+/// Unpacks the archive into `staging`, where none of it may reach out of.
+/// Returns the name it is rooted at, the last component of the path asked
+/// for, unless it is empty.
+fn unpack<R: Read>(archive: &mut Archive<R>, staging: &Path) -> Result<Option<OsString>> {
+    let mut top = None;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.into_owned();
+        let escapes = |path: &Path| {
+            path.is_absolute()
+                || path
+                    .components()
+                    .any(|component| component == Component::ParentDir)
+        };
+        let first = match name.components().next() {
+            Some(Component::Normal(first)) => first.to_owned(),
+            _ => return Err(bad_entry(&name)),
+        };
+        if escapes(&name) || *top.get_or_insert_with(|| first.clone()) != first {
+            return Err(bad_entry(&name));
+        }
+        if entry.header().entry_type() == EntryType::Link {
+            // Names what it links to as a path in the archive.
+            match entry.link_name()? {
+                Some(linked) if !escapes(&linked) => {}
+                _ => return Err(bad_entry(&name)),
+            }
+        }
+        // Not owned by the archive's users, so by us.
+        entry.unpack_in(staging)?;
+    }
+    Ok(top)
+}
+
+/// Moves `from` to `to`, merging a directory into one already there.
+fn move_to(from: &Path, to: &Path) -> Result<()> {
+    if from.symlink_metadata()?.is_dir() && to.is_dir() {
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            move_to(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else {
+        fs::rename(from, to)?;
+    }
+    Ok(())
+}
+
+fn bad_entry(name: &Path) -> Error {
+    Error::Docker(format!("unexpected path in archive: {}", name.display()))
+}
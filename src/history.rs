@@ -0,0 +1,214 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::error::{Error, Result};
+use super::{Instruction, State};
+
+/// Name of the branch a session starts on.
+pub const MAIN_BRANCH: &str = "main";
+
+/// The states one line of work went through, newest last, plus what `undo` took away.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Branch {
+    stack: Vec<State>,
+    /// Whole stacks as they were before each undo.
+    redo: Vec<Vec<State>>,
+}
+
+/// Every branch of a session. Branches share the states from before they
+/// were checkpointed, so together they form a tree rooted at the `FROM`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct History {
+    branches: BTreeMap<String, Branch>,
+    branch: String,
+}
+
+impl History {
+    pub fn new(initial: State) -> History {
+        let mut branches = BTreeMap::new();
+        branches.insert(
+            MAIN_BRANCH.to_owned(),
+            Branch {
+                stack: vec![initial],
+                redo: vec![],
+            },
+        );
+        History {
+            branches,
+            branch: MAIN_BRANCH.to_owned(),
+        }
+    }
+
+    pub fn current(&self) -> &State {
+        self.stack().last().unwrap()
+    }
+
+    pub fn current_mut(&mut self) -> &mut State {
+        self.stack_mut().last_mut().unwrap()
+    }
+
+    /// Name of the branch being worked on.
+    pub fn branch(&self) -> &str {
+        &self.branch
+    }
+
+    /// Each branch's name and newest state, by name.
+    pub fn branches(&self) -> impl Iterator<Item = (&str, &State)> {
+        self.branches
+            .iter()
+            .map(|(name, branch)| (name.as_str(), branch.stack.last().unwrap()))
+    }
+
+    /// Newest state of the named branch.
+    pub fn tip(&self, name: &str) -> Option<&State> {
+        self.branches.get(name).map(|b| b.stack.last().unwrap())
+    }
+
+    /// Every state kept, including those `redo` could bring back.
+    pub fn states_mut(&mut self) -> impl Iterator<Item = &mut State> {
+        self.branches.values_mut().flat_map(|branch| {
+            branch
+                .stack
+                .iter_mut()
+                .chain(branch.redo.iter_mut().flat_map(|stack| stack.iter_mut()))
+        })
+    }
+
+    /// Starts a new branch from the current state and switches to it.
+    pub fn checkpoint(&mut self, name: &str) -> Result<()> {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(Error::BadArgument(format!(
+                "checkpoint: bad branch name: {:?}",
+                name
+            )));
+        }
+        if self.branches.contains_key(name) {
+            return Err(Error::BadArgument(format!(
+                "checkpoint: branch {} already exists",
+                name
+            )));
+        }
+        let branch = Branch {
+            stack: self.stack().clone(),
+            redo: vec![],
+        };
+        self.branches.insert(name.to_owned(), branch);
+        self.branch = name.to_owned();
+        Ok(())
+    }
+
+    /// Switches to another branch, carrying over settings like `debug`.
+    pub fn checkout(&mut self, name: &str) -> Result<()> {
+        if !self.branches.contains_key(name) {
+            return Err(Error::BadArgument(format!(
+                "checkout: no branch {} (see `branches`)",
+                name
+            )));
+        }
+        let current = self.current().clone();
+        self.branch = name.to_owned();
+        let state = self.current_mut();
+        state.debug = current.debug;
+        state.tty = current.tty;
+        Ok(())
+    }
+
+    /// Records a new state. Anything undone can no longer be redone.
+    pub fn push(&mut self, state: State) {
+        let branch = self.branch_mut();
+        branch.stack.push(state);
+        branch.redo.clear();
+    }
+
+    /// Drops the newest state, e.g. because its image failed to build.
+    pub fn discard(&mut self) -> Option<State> {
+        let stack = self.stack_mut();
+        if stack.len() > 1 {
+            stack.pop()
+        } else {
+            None
+        }
+    }
+
+    /// Goes back to the state before the last recipe change.
+    /// Settings like `debug` are kept as they are now.
+    pub fn undo(&mut self) -> Result<()> {
+        let current = self.current().clone();
+        let index = self
+            .stack()
+            .iter()
+            .rposition(|state| state.lines != current.lines)
+            .ok_or_else(|| Error::BadArgument("nothing to undo".to_owned()))?;
+
+        self.rewind(index, &current);
+        Ok(())
+    }
+
+    /// Prepares to remove layer `layer` (as numbered by `layers`): goes back to the
+    /// last state without it and returns the instructions after that but it,
+    /// which then need rebuilding on top of it.
+    pub fn undo_layer(&mut self, layer: usize) -> Result<Vec<Instruction>> {
+        let current = self.current().clone();
+        if layer == 0 || layer >= current.lines.len() {
+            return Err(Error::BadArgument(format!(
+                "no layer {} to undo (see `layers`)",
+                layer
+            )));
+        }
+
+        let index = self
+            .stack()
+            .iter()
+            .rposition(|state| {
+                // A command can add more than one layer, as `RUN` and `WORKDIR`.
+                let kept = state.lines.len();
+                kept <= layer && state.lines[..] == current.lines[..kept]
+            })
+            .ok_or_else(|| Error::BadArgument(format!("layer {} was never built", layer)))?;
+
+        let kept = self.stack()[index].lines.len();
+        self.rewind(index, &current);
+        Ok((kept..current.lines.len())
+            .filter(|&i| i != layer)
+            .map(|i| current.lines[i].clone())
+            .collect())
+    }
+
+    /// Pushes a rebuilt layer without forgetting what can be redone.
+    pub fn push_rebuilt(&mut self, state: State) {
+        self.stack_mut().push(state);
+    }
+
+    /// Puts back what the last undo took away.
+    pub fn redo(&mut self) -> Result<()> {
+        let branch = self.branch_mut();
+        let stack = branch
+            .redo
+            .pop()
+            .ok_or_else(|| Error::BadArgument("nothing to redo".to_owned()))?;
+        branch.stack = stack;
+        Ok(())
+    }
+
+    fn rewind(&mut self, index: usize, current: &State) {
+        let branch = self.branch_mut();
+        branch.redo.push(branch.stack.clone());
+        branch.stack.truncate(index + 1);
+        let state = self.current_mut();
+        state.debug = current.debug;
+        state.tty = current.tty;
+    }
+
+    fn stack(&self) -> &Vec<State> {
+        &self.branches[&self.branch].stack
+    }
+
+    fn stack_mut(&mut self) -> &mut Vec<State> {
+        &mut self.branch_mut().stack
+    }
+
+    fn branch_mut(&mut self) -> &mut Branch {
+        self.branches.get_mut(&self.branch).unwrap()
+    }
+}
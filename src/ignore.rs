@@ -0,0 +1,211 @@
+//! Filesystem changes that don't make a command worth recording,
+//! like shell history, temporary files and package manager caches.
+
+use std::fs;
+use std::io::Read;
+
+use serde::{Deserialize, Serialize};
+use tar::Archive;
+
+use super::error::Result;
+use super::runtime::{Change, ChangeKind, ContainerRuntime};
+use super::scratch::ScratchContainers;
+use super::State;
+
+/// Ignored whatever the distro.
+const COMMON: &[&str] = &[
+    "/root/.*_history",
+    "/root/.cache/**",
+    "/tmp/**",
+    "/var/tmp/**",
+    "/var/log/**",
+    "*.log",
+    "*.pyc",
+    "__pycache__",
+];
+
+/// Ignored on distros whose `/etc/os-release` `ID` or `ID_LIKE` is one of the first.
+const DISTROS: &[(&[&str], &[&str])] = &[
+    (&["alpine"], &["/var/cache/apk/**", "/etc/apk/cache/**"]),
+    (
+        &["debian"],
+        &[
+            "/var/cache/apt/**",
+            "/var/lib/apt/lists/**",
+            "/var/cache/debconf/*-old",
+            "/var/lib/dpkg/*-old",
+        ],
+    ),
+    (
+        &["fedora", "rhel", "centos"],
+        &["/var/cache/dnf/**", "/var/cache/yum/**"],
+    ),
+    (&["arch"], &["/var/cache/pacman/**"]),
+];
+
+/// Glob patterns for paths whose changes don't count as a state change.
+///
+/// `*` and `?` match within one path component, `**` across them. A pattern
+/// without a `/` is matched against the last component only.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IgnoreRules {
+    /// Toggled with `ignore on` / `ignore off`.
+    pub enabled: bool,
+    pub patterns: Vec<String>,
+}
+
+impl Default for IgnoreRules {
+    /// Enabled, but with nothing to ignore.
+    fn default() -> Self {
+        IgnoreRules {
+            enabled: true,
+            patterns: vec![],
+        }
+    }
+}
+
+impl IgnoreRules {
+    /// The defaults for the distro of the state's image, as amended by the
+    /// config file: `$XDG_CONFIG_HOME/dockershell/ignore`. That has a pattern
+    /// per line, to add it, or `!pattern` to drop a default.
+    pub fn for_image(runtime: &dyn ContainerRuntime, state: &State) -> Result<IgnoreRules> {
+        let scratch = ScratchContainers::new();
+        let container = scratch.get(runtime, state)?;
+        let rules = IgnoreRules::for_container(runtime, &container);
+        scratch.remove_all(runtime)?;
+        Ok(rules)
+    }
+
+    fn for_container(runtime: &dyn ContainerRuntime, container: &str) -> IgnoreRules {
+        let mut patterns: Vec<String> = COMMON.iter().map(|p| p.to_string()).collect();
+        let distro = os_release(runtime, container).unwrap_or_default();
+        for (names, defaults) in DISTROS {
+            if distro.iter().any(|id| names.contains(&id.as_str())) {
+                patterns.extend(defaults.iter().map(|p| p.to_string()));
+            }
+        }
+
+        let config = dirs::config_dir()
+            .map(|dir| dir.join("dockershell").join("ignore"))
+            .and_then(|path| fs::read_to_string(path).ok())
+            .unwrap_or_default();
+        for line in config.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with('!') {
+                patterns.retain(|pattern| *pattern != line[1..]);
+            } else if !patterns.iter().any(|pattern| pattern == line) {
+                patterns.push(line.to_owned());
+            }
+        }
+        IgnoreRules {
+            enabled: true,
+            patterns,
+        }
+    }
+
+    pub fn ignores(&self, path: &str) -> bool {
+        self.enabled && self.patterns.iter().any(|pattern| matches(pattern, path))
+    }
+
+    /// Whether any of `changes` is worth recording. A changed directory
+    /// doesn't count when there are changes inside it, which say more.
+    pub fn any_significant(&self, changes: &[Change]) -> bool {
+        changes.iter().any(|change| {
+            let inside = format!("{}/", change.path.trim_end_matches('/'));
+            let explained = change.kind == ChangeKind::Modified
+                && changes.iter().any(|other| other.path.starts_with(&inside));
+            !explained && !self.ignores(&change.path)
+        })
+    }
+
+    /// `changes` but the ignored ones, and directories only changed by them.
+    pub fn unignored(&self, changes: &[Change]) -> Vec<Change> {
+        changes
+            .iter()
+            .filter(|change| {
+                let inside = format!("{}/", change.path.trim_end_matches('/'));
+                let mut within = changes
+                    .iter()
+                    .filter(|other| other.path.starts_with(&inside))
+                    .peekable();
+                let only_ignored_within = change.kind == ChangeKind::Modified
+                    && within.peek().is_some()
+                    && within.all(|other| self.ignores(&other.path));
+                !only_ignored_within && !self.ignores(&change.path)
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+/// `ID` and `ID_LIKE` of the container's distro.
+fn os_release(runtime: &dyn ContainerRuntime, container: &str) -> Result<Vec<String>> {
+    let mut archive = Archive::new(runtime.download_path(container, "/etc/os-release")?);
+    let mut text = String::new();
+    if let Some(entry) = archive.entries()?.next() {
+        entry?.read_to_string(&mut text)?;
+    }
+    Ok(text
+        .lines()
+        .filter(|line| line.starts_with("ID=") || line.starts_with("ID_LIKE="))
+        .flat_map(|line| {
+            line[line.find('=').unwrap() + 1..]
+                .trim_matches('"')
+                .split(' ')
+        })
+        .map(|id| id.to_owned())
+        .collect())
+}
+
+fn matches(pattern: &str, path: &str) -> bool {
+    if pattern.contains('/') {
+        glob(pattern.as_bytes(), path.as_bytes())
+    } else {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        glob(pattern.as_bytes(), name.as_bytes())
+    }
+}
+
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    if pattern.starts_with(b"**") {
+        let rest = &pattern[2..];
+        // `a/**/b` matches `a/b` too.
+        return (rest.starts_with(b"/") && glob(&rest[1..], text))
+            || (0..=text.len()).any(|skip| glob(rest, &text[skip..]));
+    }
+    match pattern.first() {
+        None => text.is_empty(),
+        Some(b'*') => {
+            let segment = text.iter().position(|&c| c == b'/').unwrap_or(text.len());
+            (0..=segment).any(|skip| glob(&pattern[1..], &text[skip..]))
+        }
+        Some(b'?') => !text.is_empty() && text[0] != b'/' && glob(&pattern[1..], &text[1..]),
+        Some(c) => text.first() == Some(c) && glob(&pattern[1..], &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::matches;
+
+    #[test]
+    fn globs_match_paths() {
+        assert!(matches("/tmp/**", "/tmp/a/b"));
+        assert!(!matches("/tmp/**", "/tmp"));
+        assert!(!matches("/tmp/**", "/tmpfile"));
+        assert!(matches("/root/.*_history", "/root/.ash_history"));
+        assert!(!matches("/root/*", "/root/a/b"));
+        assert!(matches("/usr/**/site-packages", "/usr/site-packages"));
+        assert!(matches(
+            "/usr/**/site-packages",
+            "/usr/lib/python3/site-packages"
+        ));
+        assert!(matches("*.pyc", "/usr/lib/x/__pycache__/a.cpython-37.pyc"));
+        assert!(!matches("*.pyc", "/usr/lib/a.pyc/b"));
+        assert!(matches("/var/lib/dpkg/*-old", "/var/lib/dpkg/status-old"));
+        assert!(matches("/etc/host?", "/etc/hosts"));
+        assert!(!matches("/etc/host?", "/etc/host/"));
+    }
+}
@@ -0,0 +1,269 @@
+//! Editing a line at the prompt: whether the shell has all of it yet,
+//! colors, and hints from the layers recorded so far.
+
+use std::borrow::Cow::{self, Owned};
+
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::{Context, Helper};
+
+use super::complete::{Completions, BUILT_INS};
+use super::dockerfile::heredoc_delimiters;
+use super::error::Result;
+use super::{Recording, State};
+
+const BUILT_IN: &str = "\x1b[1;34m";
+/// `!!`, which records.
+const ALWAYS: &str = "\x1b[1;32m";
+/// `?`, which only tries.
+const NEVER: &str = "\x1b[1;33m";
+const STRING: &str = "\x1b[36m";
+const HINT: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+/// Whether `text` is a whole command for the shell: not ending in a `\`,
+/// with its quotes closed and the bodies of its heredocs ended.
+pub(crate) fn is_complete(text: &str) -> bool {
+    let mut quote = None;
+    let mut continued = false;
+    // Heredocs whose bodies are still to come, with whether tabs are stripped (`<<-`).
+    let mut heredocs: Vec<(String, bool)> = vec![];
+    // Lines of the command since the last heredoc body, to find more in.
+    let mut command = String::new();
+    for line in text.split('\n') {
+        if quote.is_none() && !continued {
+            if let Some((delimiter, strip_tabs)) = heredocs.first() {
+                let line = if *strip_tabs {
+                    line.trim_start_matches('\t')
+                } else {
+                    line
+                };
+                if line == delimiter {
+                    heredocs.remove(0);
+                }
+                continue;
+            }
+        }
+
+        command.push_str(line);
+        command.push('\n');
+        let bytes = line.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            match (quote, bytes[i]) {
+                (Some(b'\''), b'\'') => quote = None,
+                (Some(b'\''), _) => {}
+                (_, b'\\') => i += 1,
+                (Some(b'"'), b'"') => quote = None,
+                (Some(_), _) => {}
+                (None, c) if c == b'\'' || c == b'"' => quote = Some(c),
+                (None, b'#') if i == 0 || bytes[i - 1].is_ascii_whitespace() => break,
+                _ => {}
+            }
+            i += 1;
+        }
+        // Past the end when the last character was escaped.
+        continued = i > bytes.len();
+        if quote.is_none() && !continued {
+            heredocs.extend(heredoc_delimiters(&command));
+            command.clear();
+        }
+    }
+    quote.is_none() && !continued && heredocs.is_empty()
+}
+
+/// `line` with built-ins, the `!!` and `?` prefixes and strings colored.
+pub(crate) fn highlight(line: &str) -> String {
+    let mut colored = String::with_capacity(line.len() * 2);
+    let trimmed = line.trim_start();
+    colored.push_str(&line[..line.len() - trimmed.len()]);
+    let (recording, _) = Recording::of(line);
+    let (prefix, color) = match recording {
+        Recording::Auto => ("", ""),
+        Recording::Always => ("!!", ALWAYS),
+        Recording::Never => ("?", NEVER),
+    };
+    if !prefix.is_empty() {
+        colored.push_str(&format!("{}{}{}", color, prefix, RESET));
+    }
+
+    let rest = &trimmed[prefix.len()..];
+    let command = rest.trim_start();
+    colored.push_str(&rest[..rest.len() - command.len()]);
+    let end = command.find(char::is_whitespace).unwrap_or(command.len());
+    if BUILT_INS.contains(&&command[..end]) {
+        colored.push_str(&format!("{}{}{}", BUILT_IN, &command[..end], RESET));
+    } else {
+        colored.push_str(&color_strings(&command[..end]));
+    }
+    colored.push_str(&color_strings(&command[end..]));
+    colored
+}
+
+/// `text` with what is quoted in it colored, quotes included.
+fn color_strings(text: &str) -> String {
+    let mut colored = String::with_capacity(text.len());
+    let mut quote = None;
+    let mut escaped = false;
+    for c in text.chars() {
+        let closes = match quote {
+            Some('\'') => c == '\'',
+            Some(q) => !escaped && c == q,
+            None => false,
+        };
+        let opens = quote.is_none() && !escaped && (c == '\'' || c == '"');
+        // Backslashes escape but in single quotes.
+        escaped = !escaped && c == '\\' && quote != Some('\'');
+        if opens {
+            quote = Some(c);
+            colored.push_str(STRING);
+        }
+        colored.push(c);
+        if closes {
+            quote = None;
+            colored.push_str(RESET);
+        }
+    }
+    if quote.is_some() {
+        colored.push_str(RESET);
+    }
+    colored
+}
+
+/// Completes, colors and hints at what is typed at the prompt.
+pub(crate) struct PromptHelper<'a> {
+    completions: Completions<'a>,
+    /// Commands of the recorded layers that can be typed again, oldest first.
+    recorded: Vec<String>,
+}
+
+impl<'a> PromptHelper<'a> {
+    pub(crate) fn new(completions: Completions<'a>) -> PromptHelper<'a> {
+        PromptHelper {
+            completions,
+            recorded: vec![],
+        }
+    }
+
+    /// Helps with lines run in `state` from now on.
+    pub(crate) fn set_state(&mut self, state: &State) {
+        self.completions.set_state(state);
+        self.recorded = state
+            .lines
+            .iter()
+            .skip(1)
+            .filter_map(|instruction| instruction.to_shell())
+            .collect();
+    }
+
+    /// Removes the containers completion listed images in.
+    pub(crate) fn remove_containers(&self) -> Result<()> {
+        self.completions.remove_containers()
+    }
+
+    /// The rest of the latest recorded command that starts with `line`,
+    /// up to the end of its first line.
+    pub(crate) fn suggest(&self, line: &str, pos: usize) -> Option<String> {
+        if line.trim().is_empty() || pos < line.len() {
+            return None;
+        }
+        self.recorded
+            .iter()
+            .rev()
+            .filter(|command| command.starts_with(line))
+            .map(|command| command[line.len()..].lines().next().unwrap_or(""))
+            .find(|rest| !rest.is_empty())
+            .map(|rest| rest.to_owned())
+    }
+}
+
+impl<'a> Completer for PromptHelper<'a> {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        self.completions.complete(line, pos, ctx)
+    }
+}
+
+impl<'a> Hinter for PromptHelper<'a> {
+    fn hint(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Option<String> {
+        self.suggest(line, pos)
+    }
+}
+
+impl<'a> Highlighter for PromptHelper<'a> {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        Owned(highlight(line))
+    }
+
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        Owned(format!("{}{}{}", HINT, hint, RESET))
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize) -> bool {
+        // Any character can start or end a string or a built-in.
+        true
+    }
+}
+
+impl<'a> Helper for PromptHelper<'a> {}
+
+#[cfg(test)]
+mod tests {
+    use super::{highlight, is_complete, PromptHelper};
+    use crate::complete::Completions;
+    use crate::{FakeRuntime, Instruction, State};
+
+    #[test]
+    fn incomplete_shell_input_is_noticed() {
+        assert!(is_complete("echo hi"));
+        assert!(is_complete("echo 'a \\' \"b \\\" c\" # it's"));
+        assert!(!is_complete("apk add \\"));
+        assert!(is_complete("apk add \\\n  curl"));
+        assert!(!is_complete("echo 'one\ntwo"));
+        assert!(is_complete("echo 'one\ntwo'"));
+        assert!(!is_complete("echo \"a \\\"\nb"));
+        assert!(!is_complete("cat <<EOF >/f\nhello"));
+        assert!(is_complete("cat <<EOF >/f\nhello\nEOF"));
+        assert!(!is_complete("cat <<-'END' >/f\n\thello\n"));
+        assert!(is_complete("cat <<-'END' >/f\n\thello\n\tEND"));
+        assert!(is_complete(
+            "echo \"<<EOF\" && cat <<< hi && echo $((1 << 2))"
+        ));
+        assert!(is_complete("echo $((1<<2)) '<<EOF'"));
+        assert!(!is_complete("cat<<EOF"));
+        assert!(is_complete("cat<<EOF\nhi\nEOF"));
+        assert!(!is_complete("echo 'a\nb' <<EOF\nc"));
+    }
+
+    #[test]
+    fn lines_are_colored_and_hinted() {
+        assert_eq!(
+            highlight(" !! layers"),
+            " \x1b[1;32m!!\x1b[0m \x1b[1;34mlayers\x1b[0m"
+        );
+        assert_eq!(
+            highlight("?echo 'a \"b' x"),
+            "\x1b[1;33m?\x1b[0mecho \x1b[36m'a \"b'\x1b[0m x"
+        );
+        assert_eq!(highlight("echo \"a"), "echo \x1b[36m\"a\x1b[0m");
+
+        let runtime = FakeRuntime::new();
+        let mut helper = PromptHelper::new(Completions::new(&runtime));
+        let mut state = State::test();
+        state.lines.push(Instruction::run("apk add curl"));
+        state.lines.push(Instruction::run("apk add git\necho done"));
+        helper.set_state(&state);
+        assert_eq!(helper.suggest("apk add ", 8), Some("git".to_owned()));
+        assert_eq!(helper.suggest("apk add c", 9), Some("url".to_owned()));
+        assert_eq!(helper.suggest("apk add c", 3), None);
+        assert_eq!(helper.suggest("apk add curl", 12), None);
+        assert_eq!(helper.suggest("", 0), None);
+    }
+}
@@ -0,0 +1,374 @@
+//! Typed Dockerfile instructions - what a session's recipe is made of.
+
+use std::fmt;
+
+use super::dockerfile::{self, Arguments, Heredoc, Node, ParseError};
+
+/// What shell form commands are run by.
+const SHELL: &[&str] = &["/bin/sh", "-c"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+    From {
+        flags: Vec<String>,
+        image: String,
+        alias: Option<String>,
+    },
+    Run {
+        flags: Vec<String>,
+        args: Arguments,
+        heredocs: Vec<Heredoc>,
+    },
+    Workdir(String),
+    /// Variables in the order given. As in a Dockerfile, `$NAME` in a
+    /// value is substituted and `\$` is a plain `$`.
+    Env(Vec<(String, String)>),
+    Copy {
+        flags: Vec<String>,
+        sources: Vec<String>,
+        dest: String,
+        heredocs: Vec<Heredoc>,
+    },
+    User(String),
+    Arg {
+        name: String,
+        /// As written, quotes included.
+        default: Option<String>,
+    },
+    /// Instructions dockershell does not interpret (CMD, LABEL, ...), kept verbatim.
+    Other {
+        keyword: String,
+        args: String,
+    },
+}
+
+impl Instruction {
+    pub fn from_image(image: &str) -> Instruction {
+        Instruction::From {
+            flags: vec![],
+            image: image.to_owned(),
+            alias: None,
+        }
+    }
+
+    /// Shell form `RUN`, but for a command over several lines: the classic
+    /// builder only takes that in exec form, run by the shell as it would be.
+    pub fn run(command: &str) -> Instruction {
+        let args = if command.contains('\n') {
+            Arguments::Exec(
+                SHELL
+                    .iter()
+                    .map(|arg| arg.to_string())
+                    .chain(Some(command.to_owned()))
+                    .collect(),
+            )
+        } else {
+            Arguments::Shell(command.to_owned())
+        };
+        Instruction::Run {
+            flags: vec![],
+            args,
+            heredocs: vec![],
+        }
+    }
+
+    pub fn workdir(dir: &str) -> Instruction {
+        Instruction::Workdir(dir.to_owned())
+    }
+
+    pub fn keyword(&self) -> &str {
+        match self {
+            Instruction::From { .. } => "FROM",
+            Instruction::Run { .. } => "RUN",
+            Instruction::Workdir(_) => "WORKDIR",
+            Instruction::Env(_) => "ENV",
+            Instruction::Copy { .. } => "COPY",
+            Instruction::User(_) => "USER",
+            Instruction::Arg { .. } => "ARG",
+            Instruction::Other { keyword, .. } => keyword,
+        }
+    }
+
+    /// What to type at the prompt to replay this instruction, if it can be.
+    pub fn to_shell(&self) -> Option<String> {
+        match self {
+            Instruction::Run {
+                args: Arguments::Shell(command),
+                heredocs,
+                ..
+            } => {
+                // A script, as `RUN <<EOF` runs it.
+                if let [heredoc] = &heredocs[..] {
+                    if command.trim().starts_with("<<")
+                        && !command.trim().contains(char::is_whitespace)
+                    {
+                        let body = &heredoc.body;
+                        return Some(body.strip_suffix('\n').unwrap_or(body).to_owned());
+                    }
+                }
+                // The shell takes heredocs as the Dockerfile has them.
+                let mut shell = command.clone();
+                for heredoc in heredocs {
+                    shell.push_str(&format!("\n{}{}", heredoc.body, heredoc.delimiter));
+                }
+                Some(shell)
+            }
+            Instruction::Run {
+                args: Arguments::Exec(args),
+                ..
+            } => match args.split_last() {
+                Some((command, shell)) if shell == SHELL => Some(command.clone()),
+                _ => None,
+            },
+            Instruction::Workdir(dir) => Some(format!("cd {}", dir)),
+            Instruction::Env(vars) => {
+                let vars: Vec<String> = vars
+                    .iter()
+                    .map(|(name, value)| format!("{}={}", name, quote(value, true)))
+                    .collect();
+                Some(format!("export {}", vars.join(" ")))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn from_node(node: &Node) -> Result<Instruction, ParseError> {
+        let error = |message: &str| ParseError {
+            line: node.span.start_line,
+            message: format!("{}: {}", node.keyword, message),
+        };
+        let text = node.args.to_string();
+
+        Ok(match node.keyword.as_str() {
+            "FROM" => {
+                let words: Vec<&str> = text.split_whitespace().collect();
+                match words.as_slice() {
+                    [image] => Instruction::From {
+                        flags: node.flags.clone(),
+                        image: image.to_string(),
+                        alias: None,
+                    },
+                    [image, as_, alias] if as_.eq_ignore_ascii_case("as") => Instruction::From {
+                        flags: node.flags.clone(),
+                        image: image.to_string(),
+                        alias: Some(alias.to_string()),
+                    },
+                    _ => return Err(error("expected `FROM image [AS name]`")),
+                }
+            }
+            "RUN" => Instruction::Run {
+                flags: node.flags.clone(),
+                args: node.args.clone(),
+                heredocs: node.heredocs.clone(),
+            },
+            "WORKDIR" => Instruction::Workdir(text.trim().to_owned()),
+            "USER" => Instruction::User(text.trim().to_owned()),
+            "ENV" => {
+                Instruction::Env(parse_env(&text).ok_or_else(|| error("expected name=value"))?)
+            }
+            "COPY" => {
+                let mut paths = match &node.args {
+                    Arguments::Exec(args) => args.clone(),
+                    Arguments::Shell(text) => {
+                        text.split_whitespace().map(|s| s.to_owned()).collect()
+                    }
+                };
+                if paths.len() < 2 {
+                    return Err(error("expected at least one source and a destination"));
+                }
+                let dest = paths.pop().unwrap();
+                Instruction::Copy {
+                    flags: node.flags.clone(),
+                    sources: paths,
+                    dest,
+                    heredocs: node.heredocs.clone(),
+                }
+            }
+            "ARG" => {
+                let mut parts = text.trim().splitn(2, '=');
+                Instruction::Arg {
+                    name: parts.next().unwrap().to_owned(),
+                    default: parts.next().map(|s| s.to_owned()),
+                }
+            }
+            _ => {
+                let full = node.to_string();
+                Instruction::Other {
+                    keyword: node.keyword.clone(),
+                    args: full[node.keyword.len()..].trim_start().to_owned(),
+                }
+            }
+        })
+    }
+}
+
+impl fmt::Display for Instruction {
+    /// Dockerfile text. `parse_dockerfile(&x.to_string())` gives back `x`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.keyword())?;
+        match self {
+            Instruction::From {
+                flags,
+                image,
+                alias,
+            } => {
+                write_flags(f, flags)?;
+                write!(f, " {}", image)?;
+                if let Some(alias) = alias {
+                    write!(f, " AS {}", alias)?;
+                }
+            }
+            Instruction::Run {
+                flags,
+                args,
+                heredocs,
+            } => {
+                write_flags(f, flags)?;
+                write!(f, " {}", args)?;
+                write_heredocs(f, heredocs)?;
+            }
+            Instruction::Workdir(arg) | Instruction::User(arg) => write!(f, " {}", arg)?,
+            Instruction::Env(vars) => {
+                for (name, value) in vars {
+                    write!(f, " {}={}", name, quote(value, false))?;
+                }
+            }
+            Instruction::Copy {
+                flags,
+                sources,
+                dest,
+                heredocs,
+            } => {
+                write_flags(f, flags)?;
+                let mut paths = sources.clone();
+                paths.push(dest.clone());
+                if paths.iter().any(|p| p.contains(char::is_whitespace)) {
+                    write!(f, " {}", Arguments::Exec(paths))?;
+                } else {
+                    write!(f, " {}", paths.join(" "))?;
+                }
+                write_heredocs(f, heredocs)?;
+            }
+            Instruction::Arg { name, default } => {
+                write!(f, " {}", name)?;
+                if let Some(default) = default {
+                    write!(f, "={}", default)?;
+                }
+            }
+            Instruction::Other { args, .. } => write!(f, " {}", args)?,
+        }
+        Ok(())
+    }
+}
+
+fn write_flags(f: &mut fmt::Formatter, flags: &[String]) -> fmt::Result {
+    for flag in flags {
+        write!(f, " {}", flag)?;
+    }
+    Ok(())
+}
+
+fn write_heredocs(f: &mut fmt::Formatter, heredocs: &[Heredoc]) -> fmt::Result {
+    for heredoc in heredocs {
+        write!(f, "\n{}{}", heredoc.body, heredoc.delimiter)?;
+    }
+    Ok(())
+}
+
+/// Parses a whole Dockerfile into instructions.
+pub fn parse_dockerfile(text: &str) -> Result<Vec<Instruction>, ParseError> {
+    dockerfile::parse(text)?
+        .nodes
+        .iter()
+        .map(Instruction::from_node)
+        .collect()
+}
+
+/// Dockerfile text of a recipe, one instruction per line.
+pub fn to_dockerfile(instructions: &[Instruction]) -> String {
+    let lines: Vec<String> = instructions.iter().map(|i| i.to_string()).collect();
+    lines.join("\n")
+}
+
+/// `a=b "c d"=e` or the legacy single `name value` form.
+fn parse_env(text: &str) -> Option<Vec<(String, String)>> {
+    let text = text.trim();
+    let first = text.split_whitespace().next()?;
+    if !first.contains('=') {
+        let value = text[first.len()..].trim();
+        return Some(vec![(first.to_owned(), value.to_owned())]);
+    }
+    split_words(text)
+        .into_iter()
+        .map(|word| {
+            let mut parts = word.splitn(2, '=');
+            Some((parts.next()?.to_owned(), parts.next()?.to_owned()))
+        })
+        .collect()
+}
+
+/// Splits on whitespace, honouring (and removing) quotes and backslash
+/// escapes, but for `\$`, which substitution still needs.
+fn split_words(text: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote: Option<char> = None;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"'), '\\') | (None, '\\') => {
+                if let Some(next) = chars.next() {
+                    if next == '$' {
+                        word.push('\\');
+                    }
+                    word.push(next);
+                }
+            }
+            (Some(_), c) => word.push(c),
+            (None, '"') | (None, '\'') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(word.clone());
+                    word.clear();
+                    in_word = false;
+                }
+            }
+            (None, c) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    words
+}
+
+/// `value` as one word of a Dockerfile, or of the shell if `shell`, where
+/// backticks run commands.
+fn quote(value: &str, shell: bool) -> String {
+    let special = |c: char| c.is_whitespace() || "\"'\\".contains(c) || (shell && c == '`');
+    if !value.is_empty() && !value.chars().any(special) {
+        return value.to_owned();
+    }
+    let mut quoted = String::from("\"");
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            // Already escaped, for a `$` rather than a variable.
+            '\\' if chars.peek() == Some(&'$') => {}
+            '\\' | '"' => quoted.push('\\'),
+            '`' if shell => quoted.push('\\'),
+            _ => {}
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
@@ -0,0 +1,57 @@
+//! Giving up on a running command: Ctrl-C, or it running for too long.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use super::error::{Error, Result};
+
+/// Set by SIGINT while a command runs.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigint(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Watches one command. While it lives, Ctrl-C no longer kills dockershell;
+/// the previous SIGINT handler is put back on drop.
+pub(crate) struct Watch {
+    started: Instant,
+    timeout: Option<Duration>,
+    /// Ctrl-C typed in raw mode, which sends no signal.
+    typed: AtomicBool,
+    previous: libc::sighandler_t,
+}
+
+impl Watch {
+    pub(crate) fn new(timeout: Option<Duration>) -> Watch {
+        INTERRUPTED.store(false, Ordering::SeqCst);
+        let handler: extern "C" fn(libc::c_int) = on_sigint;
+        Watch {
+            started: Instant::now(),
+            timeout,
+            typed: AtomicBool::new(false),
+            previous: unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) },
+        }
+    }
+
+    pub(crate) fn interrupt(&self) {
+        self.typed.store(true, Ordering::SeqCst);
+    }
+
+    /// Err once the command should be given up on.
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.typed.load(Ordering::SeqCst) || INTERRUPTED.load(Ordering::SeqCst) {
+            return Err(Error::Interrupted);
+        }
+        match self.timeout {
+            Some(timeout) if self.started.elapsed() >= timeout => Err(Error::TimedOut(timeout)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        unsafe { libc::signal(libc::SIGINT, self.previous) };
+    }
+}
@@ -4,46 +4,114 @@
     unused_import_braces,
     unused_qualifications
 )]
-#![allow(unused_assignments,stable_features)]
-#![feature(pin)]
-#![feature(futures_api)]
-#![feature(await_macro)]
-#![feature(async_await)]
-
-use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
-use std::path::Path;
-use std::pin::Pin;
-
-use dockworker::*;
+#![allow(unused_assignments)]
+// What the locked serde_derive generates trips these on current compilers.
+#![allow(unexpected_cfgs, non_local_definitions)]
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
 use futures::executor::block_on;
-use futures::future::FutureExt;
-use futures::Future;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
-use tar::Builder;
+use serde::{Deserialize, Serialize};
 
+mod complete;
+mod context;
+mod diff;
+pub mod dockerfile;
+mod docker_api;
+mod edit;
+mod env;
+mod error;
 mod exec;
+mod fake;
+mod get;
+mod history;
+mod ignore;
+mod input;
+mod interrupt;
+mod instruction;
+mod progress;
+mod runtime;
+mod scratch;
+mod session;
+mod tty;
 
-use self::exec::{execute_command, ExecResults};
+pub use self::error::{Error, Result};
+use self::complete::Completions;
+use self::context::BuildContext;
+pub use self::context::ManagedContext;
+pub use self::diff::FileChange;
+use self::exec::{execute_command, unique_name};
+use self::input::PromptHelper;
+pub use self::scratch::ScratchContainers;
+pub use self::history::{History, MAIN_BRANCH};
+pub use self::ignore::IgnoreRules;
+pub use self::fake::{FakeCommand, FakeRuntime};
+pub use self::instruction::{parse_dockerfile, to_dockerfile, Instruction};
+use self::progress::Build;
+pub use self::session::Session;
+pub use self::runtime::{
+    Attached, Change, ChangeKind, Commit, Committing, ContainerRuntime, ContainerSpec,
+    DockerRuntime, ImageInfo,
+};
+
+/// The last layer's image, on its way.
+struct PendingImage {
+    /// Its index into `State::lines`.
+    layer: usize,
+    /// Being built, or committed from the container a command ran in.
+    image: Build,
+}
 
-type Result<T> = std::result::Result<T, ()>;
+impl PendingImage {
+    fn done(&self) -> bool {
+        self.image.progress().done()
+    }
 
-/// Future Image Name. Resolves once Docker has built the image.
-type FutureImage = Pin<Box<dyn Future<Output = Box<String>>>>;
+    /// Waits for it, showing how the build is going.
+    fn wait(mut self) -> Result<String> {
+        let what = format!("building layer {}", self.layer);
+        let progress = self.image.progress();
+        progress::wait(&mut self.image, Some(&progress), &what)
+    }
+}
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct State {
     pub debug: bool,
 
     /// Enables ascii colors and one day maybe Vi to work.
     pub tty: bool,
 
-    /// Initial commands to run.
-    pub lines: Vec<Vec<String>>,
+    /// The recipe so far. Always starts with a `FROM`.
+    /// (Initially, any further lines are commands to run.)
+    #[serde(with = "session::dockerfile_text")]
+    pub lines: Vec<Instruction>,
     pub image_name: String,
     pub pwd: String,
+    /// Where `cd -` goes.
+    #[serde(default)]
+    pub oldpwd: String,
+    /// Directories `pushd` has left, most recent first.
+    #[serde(default)]
+    pub dir_stack: Vec<String>,
+    /// What each layer that changed files changed, by index into `lines`.
+    #[serde(default)]
+    pub layer_changes: BTreeMap<usize, Vec<FileChange>>,
+    /// Changes that don't count as changing state.
+    #[serde(default)]
+    pub ignore: IgnoreRules,
+    /// Commands running longer than this are stopped, and not recorded.
+    #[serde(default)]
+    pub timeout: Option<Duration>,
     pub shell: String,
+    /// What `edit` opens files in, rather than `$VISUAL` or `$EDITOR`.
+    #[serde(skip)]
+    pub editor: Option<String>,
 }
 
 impl State {
@@ -55,6 +123,23 @@ impl State {
             ..State::default()
         }
     }
+
+    /// Image named by the leading `FROM`.
+    pub fn base_image(&self) -> &str {
+        match self.lines.first() {
+            Some(Instruction::From { image, .. }) => image,
+            other => panic!("recipe must start with FROM, not {:?}", other),
+        }
+    }
+
+    pub fn last_instruction(&self) -> &Instruction {
+        self.lines.last().unwrap()
+    }
+
+    /// The recipe as Dockerfile text.
+    pub fn dockerfile(&self) -> String {
+        to_dockerfile(&self.lines)
+    }
 }
 
 impl Default for State {
@@ -62,63 +147,149 @@ impl Default for State {
         State {
             debug: false,
             tty: true,
-            lines: vec![vec!["FROM".to_owned(), "alpine:edge".to_owned()]],
+            lines: vec![Instruction::from_image("alpine:edge")],
             image_name: "alpine:edge".to_owned(),
             pwd: String::new(),
+            oldpwd: String::new(),
+            dir_stack: vec![],
+            layer_changes: BTreeMap::new(),
+            ignore: IgnoreRules::default(),
+            timeout: None,
             shell: "/bin/sh".to_owned(),
+            editor: None,
         }
     }
 }
 
 pub trait ExecListener {
-    fn command_run(&mut self, line: &str, state: &State, line_result: Result<&LineResult>);
+    fn command_run(
+        &mut self,
+        line: &str,
+        state: &State,
+        line_result: std::result::Result<&LineResult, &Error>,
+    );
+
+    /// Before `command_run`, for a line whose prefix decides whether it is recorded.
+    fn recording_overridden(&mut self, _line: &str, _recording: Recording) {}
+
+    /// Before `command_run`, for a command stopped because it ran for longer than `after`.
+    fn command_timed_out(&mut self, _line: &str, _after: Duration) {}
+
+    /// The image for the newest layer, made by `instruction`, could not be built
+    /// or committed, so the layer has been dropped again.
+    fn layer_failed(&mut self, _instruction: &Instruction, _err: &Error) {}
 }
 
 pub struct NoOpListener {}
 
 impl ExecListener for NoOpListener {
-    fn command_run(&mut self, _line: &str, _state: &State, _line_result: Result<&LineResult>) {}
+    fn command_run(
+        &mut self,
+        _line: &str,
+        _state: &State,
+        _line_result: std::result::Result<&LineResult, &Error>,
+    ) {
+    }
 }
 
-pub fn interpreter_loop_from_stdin(initial_state: State) -> Result<()> {
+pub fn interpreter_loop_from_stdin(
+    initial_state: State,
+    runtime: &dyn ContainerRuntime,
+) -> Result<()> {
+    let session = match Session::create() {
+        Ok(session) => {
+            println!("Session {} (continue later with --resume {0})", session.name());
+            Some(session)
+        }
+        Err(err) => {
+            println!("Session will not be saved: {}", err);
+            None
+        }
+    };
+    with_stdin(runtime, |rl| {
+        interpreter_loop(
+            initial_state,
+            runtime,
+            rl,
+            &mut NoOpListener {},
+            session.as_ref(),
+        )
+    })
+}
+
+/// Carries on a saved session from where it was left.
+pub fn resume_from_stdin(session: &Session, runtime: &dyn ContainerRuntime) -> Result<()> {
+    with_stdin(runtime, |rl| resume_loop(session, runtime, rl, &mut NoOpListener {}))
+}
+
+fn with_stdin<F>(runtime: &dyn ContainerRuntime, f: F) -> Result<()>
+where
+    F: FnOnce(&mut dyn ReadPrompt) -> Result<()>,
+{
     let mut rl = ReadLinePrompt {
-        editor: Editor::<()>::new(),
+        editor: Editor::new(),
     };
+    rl.editor.set_helper(Some(PromptHelper::new(Completions::new(runtime))));
     if rl.editor.load_history("Dockerfile.dockershell").is_err() {
         println!("No previous history.");
     }
 
-    let res = interpreter_loop(initial_state, &mut rl, &mut NoOpListener {});
+    let res = f(&mut rl).and(rl.editor.helper().unwrap().remove_containers());
 
     rl.editor.save_history("Dockerfile.dockershell").unwrap();
     res
 }
 
-pub fn interpreter_loop_from_file(initial_state: State, visitor: &mut dyn ExecListener) -> Result<()> {
-    let mut rl = FilePrompt {
-        lines: initial_state
-            .lines
-            .iter()
-            .skip(1)
-            .map(|s| s.join(" "))
-            .collect(),
-    };
-
-    interpreter_loop(initial_state, &mut rl, visitor)
+pub fn interpreter_loop_from_file(
+    initial_state: State,
+    runtime: &dyn ContainerRuntime,
+    visitor: &mut dyn ExecListener,
+) -> Result<()> {
+    interpreter_loop(initial_state, runtime, &mut FilePrompt::default(), visitor, None)
 }
 
 pub trait ReadPrompt {
     fn read_line(&mut self, prompt: &str) -> std::result::Result<String, ReadlineError>;
     fn add_history_entry(&mut self, val: &str);
+    /// What the next line will be run in, for completing it.
+    fn set_state(&mut self, _state: &State) {}
+    /// An instruction to record as it is, having no command to type, before
+    /// the next line is read.
+    fn next_instruction(&mut self) -> Option<Instruction> {
+        None
+    }
 }
 
-struct ReadLinePrompt {
-    editor: Editor<()>,
+/// Prompts for the rest of a command that goes on over several lines.
+const CONTINUATION_PROMPT: &str = "> ";
+
+struct ReadLinePrompt<'a> {
+    editor: Editor<PromptHelper<'a>>,
 }
 
-impl ReadPrompt for ReadLinePrompt {
+impl<'a> ReadPrompt for ReadLinePrompt<'a> {
+    /// Reads until the shell would have the whole command: past a trailing `\`,
+    /// an unclosed quote or a heredoc's body.
     fn read_line(&mut self, prompt: &str) -> std::result::Result<String, ReadlineError> {
-        self.editor.readline(prompt)
+        let mut text = self.editor.readline(prompt)?;
+        while !input::is_complete(&text) {
+            match self.editor.readline(CONTINUATION_PROMPT) {
+                Ok(line) => {
+                    text.push('\n');
+                    text.push_str(&line);
+                }
+                // Let the shell say what is missing.
+                Err(ReadlineError::Eof) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(text)
+    }
+
+    fn set_state(&mut self, state: &State) {
+        if let Some(helper) = self.editor.helper_mut() {
+            helper.set_state(state);
+        }
     }
 
     fn add_history_entry(&mut self, val: &str) {
@@ -126,20 +297,44 @@ impl ReadPrompt for ReadLinePrompt {
     }
 }
 
-struct FilePrompt {
-    lines: Vec<String>,
+/// Replays instructions, then reads on from another prompt if there is one.
+/// Those that have a command are typed, the others recorded as they are.
+#[derive(Default)]
+struct FilePrompt<'a> {
+    lines: Vec<Instruction>,
+    then: Option<&'a mut dyn ReadPrompt>,
 }
 
-impl ReadPrompt for FilePrompt {
-    fn read_line(&mut self, _prompt: &str) -> std::result::Result<String, ReadlineError> {
-        if self.lines.is_empty() {
-            return Err(ReadlineError::Eof);
+impl<'a> ReadPrompt for FilePrompt<'a> {
+    fn read_line(&mut self, prompt: &str) -> std::result::Result<String, ReadlineError> {
+        if let Some(line) = self.lines.first().and_then(Instruction::to_shell) {
+            self.lines.remove(0);
+            return Ok(line);
+        }
+        match &mut self.then {
+            Some(rl) => rl.read_line(prompt),
+            None => Err(ReadlineError::Eof),
+        }
+    }
+
+    fn add_history_entry(&mut self, val: &str) {
+        if let Some(rl) = &mut self.then {
+            rl.add_history_entry(val);
+        }
+    }
+
+    fn set_state(&mut self, state: &State) {
+        if let Some(rl) = &mut self.then {
+            rl.set_state(state);
         }
-        let res = self.lines.remove(0);
-        Ok(res)
     }
 
-    fn add_history_entry(&mut self, _val: &str) {}
+    fn next_instruction(&mut self) -> Option<Instruction> {
+        match self.lines.first() {
+            Some(instruction) if instruction.to_shell().is_none() => Some(self.lines.remove(0)),
+            _ => None,
+        }
+    }
 }
 
 /// start from a known from image. FROM=
@@ -149,103 +344,543 @@ impl ReadPrompt for FilePrompt {
 /// run command
 /// containerNext = container.commitImage
 /// show results of command
+///
+/// The instructions after FROM are run first, as if typed, then lines are
+/// read from `rl`. If given a session, the history is saved to it after
+/// every change.
 pub fn interpreter_loop(
     initial_state: State,
+    runtime: &dyn ContainerRuntime,
     rl: &mut dyn ReadPrompt,
     visitor: &mut dyn ExecListener,
+    session: Option<&Session>,
 ) -> Result<()> {
-    let docker = Docker::connect_with_defaults().unwrap();
+    let mut rl = FilePrompt {
+        lines: initial_state.lines[1..].to_vec(),
+        then: Some(rl),
+    };
+    let mut state = initial_state.clone();
+    state.lines.clear();
+    state.lines.push(initial_state.lines[0].clone());
 
-    block_on(
-        async {
-            let mut last_image: Option<FutureImage> = None;
-            let mut state = initial_state.clone();
-            state.lines.clear();
-            state.lines.push(initial_state.lines[0].clone());
-
-            assert_eq!(initial_state.lines[0][0], "FROM");
-            state.image_name = initial_state.lines[0][1].clone();
-
-            state.lines.push(vec!["RUN".to_owned(), ("pwd").to_owned()]);
-            let exec_results = execute_command(&docker, &state).unwrap();
-            state.lines.pop();
-            state.pwd = exec_results.output.trim().to_owned();
-            let mut state_stack = vec![state];
-
-            loop {
-                let prompt = &(state_stack.last().unwrap().pwd.clone() + " ");
-                std::io::stdout().lock().flush().unwrap();
-                let readline = rl.read_line(prompt);
-                match readline {
-                    Ok(line) => {
-                        rl.add_history_entry(line.as_ref());
-
-                        if let Some(future) = last_image {
-                            let name = *await!(future);
-
-                            if docker.history_image(&name).is_err() {
-                                //Roll back to previous state....
-                                last_image = None;
-                                let bad_state = state_stack.pop().unwrap();
-                                let popped = bad_state.lines.last().unwrap();
-                                println!("Could not re-run prev command: {:?}", popped);
-                            } else {
-                                state_stack.last_mut().unwrap().image_name = name;
-                            }
-                        }
+    state.image_name = initial_state.base_image().to_owned();
 
-                        last_image = None;
-
-                        let result = parse_line(&line, &state_stack.last().unwrap(), &docker);
-                        visitor.command_run(
-                            &line,
-                            &state_stack.last().unwrap(),
-                            match &result {
-                                Ok((line_result, _)) => Ok(line_result),
-                                Err(()) => Err(()),
-                            },
-                        );
-                        match result {
-                            Ok((LineResult::NoOp(_output), None)) => {}
-                            Ok((LineResult::State(new_state, _output), fut)) => {
-                                last_image = fut;
-                                state_stack.push(new_state);
-                            }
-                            Ok((LineResult::Exit, None)) => {
-                                break;
-                            }
-                            Ok((_, _)) => unimplemented!(),
-                            Err(()) => {}
+    let managed = match session {
+        Some(session) => session.context(),
+        None => ManagedContext::temporary()?,
+    };
+    let scratch = ScratchContainers::new();
+    let result = execute_command(runtime, &scratch, &state, "pwd").and_then(|exec_results| {
+        if let Some(dirs) = exec_results.dirs {
+            state.pwd = dirs.pwd;
+            state.oldpwd = dirs.oldpwd;
+            state.dir_stack = dirs.stack;
+        }
+        let history = History::new(state);
+        let looped =
+            session_loop(history, runtime, &scratch, &managed, &mut rl, visitor, session);
+        block_on(looped)
+    });
+    result.and(scratch.remove_all(runtime))
+}
+
+/// Like `interpreter_loop`, but starting from a saved session.
+/// Images that have been pruned since are rebuilt first.
+pub fn resume_loop(
+    session: &Session,
+    runtime: &dyn ContainerRuntime,
+    rl: &mut dyn ReadPrompt,
+    visitor: &mut dyn ExecListener,
+) -> Result<()> {
+    let mut history = session.load()?;
+    let managed = session.context();
+    let scratch = ScratchContainers::new();
+    let result = reattach(runtime, &managed, &mut history).and_then(|()| {
+        let resumed = session_loop(
+            history,
+            runtime,
+            &scratch,
+            &managed,
+            rl,
+            visitor,
+            Some(session),
+        );
+        block_on(resumed)
+    });
+    result.and(scratch.remove_all(runtime))
+}
+
+/// Makes sure every image the history refers to still exists.
+fn reattach(
+    runtime: &dyn ContainerRuntime,
+    managed: &ManagedContext,
+    history: &mut History,
+) -> Result<()> {
+    let mut rebuilt: Vec<(Vec<Instruction>, String)> = vec![];
+    for state in history.states_mut() {
+        if state.lines.len() == 1 || runtime.inspect_image(&state.image_name).is_ok() {
+            continue;
+        }
+        if let Some((_, image)) = rebuilt.iter().find(|(lines, _)| *lines == state.lines) {
+            state.image_name = image.clone();
+            continue;
+        }
+        println!("Rebuilding: {}", state.last_instruction());
+        let (tag, lines) = (unique_name(), state.lines.clone());
+        state.image_name = build_now(runtime, tag, lines, managed, state.debug)?;
+        rebuilt.push((state.lines.clone(), state.image_name.clone()));
+    }
+    Ok(())
+}
+
+async fn session_loop<'a>(
+    mut history: History,
+    runtime: &'a dyn ContainerRuntime,
+    scratch: &'a ScratchContainers,
+    managed: &'a ManagedContext,
+    rl: &'a mut dyn ReadPrompt,
+    visitor: &'a mut dyn ExecListener,
+    session: Option<&'a Session>,
+) -> Result<()> {
+    let mut last_image: Option<PendingImage> = None;
+    let mut saved = None;
+    let mut status = 0;
+    // How the last command asked to be recorded.
+    let mut recording = Recording::Auto;
+
+    loop {
+        // Deal with a finished build now, so that if it failed it says so at once.
+        if last_image.as_ref().is_some_and(PendingImage::done) {
+            settle(last_image.take().unwrap(), runtime, &mut history, visitor);
+        }
+
+        if let Some(session) = session {
+            if saved.as_ref() != Some(&history) {
+                session.save(&history)?;
+                saved = Some(history.clone());
+            }
+        }
+
+        if let Some(instruction) = rl.next_instruction() {
+            if let Some(pending) = last_image.take() {
+                settle(pending, runtime, &mut history, visitor);
+            }
+            match build_instruction(runtime, managed, &mut history, instruction) {
+                Ok(building) => last_image = Some(building),
+                Err(err) => print_error(&err),
+            }
+            continue;
+        }
+
+        let mut prompt = format!("[{}] {} ", history.branch(), history.current().pwd);
+        if status != 0 {
+            prompt += &format!("[exit {}] ", status);
+        }
+        match recording {
+            Recording::Auto => {}
+            Recording::Always => prompt += "[recorded anyway] ",
+            Recording::Never => prompt += "[not recorded] ",
+        }
+        if let Some(pending) = &last_image {
+            if !pending.done() {
+                prompt += &format!("[building layer {}] ", pending.layer);
+            }
+        }
+        std::io::stdout().lock().flush().unwrap();
+        rl.set_state(history.current());
+        let readline = rl.read_line(&prompt);
+        match readline {
+            Ok(line) => {
+                rl.add_history_entry(line.as_ref());
+
+                // Looking at the history needn't wait for the last layer's image.
+                if !reads_history_only(&line) {
+                    if let Some(pending) = last_image.take() {
+                        settle(pending, runtime, &mut history, visitor);
+                    }
+                }
+                let state = history.current();
+
+                recording = Recording::of(&line).0;
+                if recording != Recording::Auto {
+                    visitor.recording_overridden(&line, recording);
+                }
+                let result = parse_line(&line, state, runtime, scratch);
+                if let Err(Error::TimedOut(after)) = &result {
+                    visitor.command_timed_out(&line, *after);
+                }
+                visitor.command_run(
+                    &line,
+                    state,
+                    match &result {
+                        Ok((line_result, _)) => Ok(line_result),
+                        Err(err) => Err(err),
+                    },
+                );
+                status = match &result {
+                    Ok((LineResult::Failed(code, _), _)) => *code,
+                    _ => 0,
+                };
+                match result {
+                    Ok((LineResult::NoOp(_output), None)) => {}
+                    Ok((LineResult::Tried(_output), None)) => {}
+                    Ok((LineResult::Failed(code, _output), None)) => println!(
+                        "Not recorded: exit status {} (prefix the command with !! to record it anyway)",
+                        code
+                    ),
+                    Ok((LineResult::State(new_state, _output), fut)) => {
+                        last_image = fut.map(|image| PendingImage {
+                            layer: new_state.lines.len() - 1,
+                            image,
+                        });
+                        history.push(new_state);
+                    }
+                    Ok((LineResult::Undo(None), None)) => match history.undo() {
+                        Ok(()) => println!("Undone, back to: {}", history.current().last_instruction()),
+                        Err(err) => println!("{}", err),
+                    },
+                    Ok((LineResult::Undo(Some(layer)), None)) => {
+                        if let Err(err) = rebuild_without(runtime, managed, &mut history, layer) {
+                            print_error(&err);
                         }
                     }
-                    Err(ReadlineError::Interrupted) => {
-                        println!("CTRL-C");
-                        break;
+                    Ok((LineResult::Edit(path), None)) => {
+                        match edit_file(runtime, scratch, managed, &mut history, &path) {
+                            Ok(building) => last_image = building,
+                            Err(err) => println!("{}", err),
+                        }
                     }
-                    Err(ReadlineError::Eof) => {
-                        println!("CTRL-D");
-                        break;
+                    Ok((LineResult::Copy { flags, source, dest }, None)) => {
+                        match copy_file(runtime, managed, &mut history, flags, &source, dest) {
+                            Ok(building) => last_image = Some(building),
+                            Err(err) => println!("{}", err),
+                        }
+                    }
+                    Ok((LineResult::Redo, None)) => match history.redo() {
+                        Ok(()) => println!("Redone: {}", history.current().last_instruction()),
+                        Err(err) => println!("{}", err),
+                    },
+                    Ok((LineResult::Checkpoint(name), None)) => {
+                        if let Err(err) = history.checkpoint(&name) {
+                            println!("{}", err);
+                        }
                     }
-                    Err(err) => {
-                        println!("Error: {:?}", err);
+                    Ok((LineResult::Checkout(name), None)) => {
+                        if let Err(err) = history.checkout(&name) {
+                            println!("{}", err);
+                        }
+                    }
+                    Ok((LineResult::Branches, None)) => print_branches(&history),
+                    Ok((LineResult::Exit, None)) => {
+                        let name = choose_branch(&history, rl);
+                        let lines = &history.tip(&name).unwrap().lines;
+                        println!("Dockerfile of {}:", name);
+                        print_dockerfile(lines);
+                        // It can't be built without the files it copies.
+                        if !managed.sources(lines).is_empty() {
+                            let dir = choose_export_dir(session, rl);
+                            match managed.export(lines, &dir) {
+                                Ok(()) => println!(
+                                    "Dockerfile and the files it copies written to {}",
+                                    dir.display()
+                                ),
+                                Err(err) => println!("Could not export: {}", err),
+                            }
+                        }
                         break;
                     }
+                    Ok((_, _)) => unimplemented!(),
+                    Err(err) => println!("{}", err),
                 }
             }
-            Ok(())
-        },
-    )
+            // As in other shells, Ctrl-C just drops what was typed.
+            Err(ReadlineError::Interrupted) => {}
+            Err(ReadlineError::Eof) => {
+                println!("CTRL-D");
+                break;
+            }
+            Err(err) => {
+                println!("Error: {:?}", err);
+                break;
+            }
+        }
+    }
+
+    // Don't leave the last command's container behind.
+    if let Some(pending) = last_image {
+        settle(pending, runtime, &mut history, visitor);
+    }
+    if let Some(session) = session {
+        session.save(&history)?;
+    }
+    Ok(())
+}
+
+/// Gives the newest state the image made for it or, if that could not be
+/// made, drops the state again.
+fn settle(
+    pending: PendingImage,
+    runtime: &dyn ContainerRuntime,
+    history: &mut History,
+    visitor: &mut dyn ExecListener,
+) {
+    let built = pending
+        .wait()
+        .and_then(|name| runtime.inspect_image(&name).map(|_| name));
+    match built {
+        Ok(name) => history.current_mut().image_name = name,
+        Err(err) => {
+            //Roll back to previous state....
+            let bad_state = history.discard().unwrap();
+            let popped = bad_state.last_instruction();
+            println!("Not recorded: {}", popped);
+            print_error(&err);
+            visitor.layer_failed(popped, &err);
+        }
+    }
+}
+
+/// With what the failing build step printed, if it was one.
+fn print_error(err: &Error) {
+    if let Error::BuildFailed { output, .. } = err {
+        print!("{}", output);
+    }
+    println!("{}", err);
 }
 
-fn print_dockerfile(lines: &[Vec<String>]) {
-    for l in lines.iter() {
-        println!("{}", l.join(" "));
+fn print_branches(history: &History) {
+    for (name, state) in history.branches() {
+        let marker = if name == history.branch() { "*" } else { " " };
+        println!(
+            "{} {} ({} layers): {}",
+            marker,
+            name,
+            state.lines.len(),
+            state.last_instruction()
+        );
     }
 }
 
-fn print_layers(lines: &[Vec<String>]) {
+/// Asks which branch to export when there is more than one.
+fn choose_branch(history: &History, rl: &mut dyn ReadPrompt) -> String {
+    if history.branches().count() == 1 {
+        return history.branch().to_owned();
+    }
+    print_branches(history);
+    loop {
+        let prompt = format!("Export which branch? [{}] ", history.branch());
+        let name = match rl.read_line(&prompt) {
+            Ok(name) => name.trim().to_owned(),
+            Err(_) => String::new(),
+        };
+        if name.is_empty() {
+            return history.branch().to_owned();
+        }
+        if history.tip(&name).is_some() {
+            return name;
+        }
+        println!("No branch {}", name);
+    }
+}
+
+/// Asks where to write the Dockerfile and its build context.
+fn choose_export_dir(session: Option<&Session>, rl: &mut dyn ReadPrompt) -> PathBuf {
+    let default = match session {
+        Some(session) => format!("dockershell-{}", session.name()),
+        None => "dockershell-build".to_owned(),
+    };
+    let prompt = format!("Write them to which directory? [{}] ", default);
+    let dir = match rl.read_line(&prompt) {
+        Ok(dir) => dir.trim().to_owned(),
+        Err(_) => String::new(),
+    };
+    PathBuf::from(if dir.is_empty() { default } else { dir })
+}
+
+fn print_dockerfile(lines: &[Instruction]) {
+    println!("{}", to_dockerfile(lines));
+}
+
+fn print_layers(lines: &[Instruction]) {
     for (i, l) in lines.iter().enumerate() {
-        println!("{}: {:?}", i, l);
+        println!("{}: {}", i, l);
+    }
+}
+
+/// Removes layer `layer` from the recipe, rebuilding every later layer on
+/// top of the image from before it.
+fn rebuild_without(
+    runtime: &dyn ContainerRuntime,
+    managed: &ManagedContext,
+    history: &mut History,
+    layer: usize,
+) -> Result<()> {
+    let before = history.current().clone();
+    let later = history.undo_layer(layer)?;
+    println!("Undone: {}", before.lines[layer]);
+
+    // What they changed isn't known once the layers below are different.
+    for instruction in later {
+        let mut state = history.current().clone();
+        // Where the shell is comes from the `WORKDIR`s left.
+        if let Instruction::Workdir(ref dir) = instruction {
+            state.pwd = in_container(&state, dir);
+        }
+        println!("Rebuilding: {}", instruction);
+        // On the layer before, as `build_layer` does.
+        let lines = vec![Instruction::from_image(&state.image_name), instruction.clone()];
+        state.image_name = build_now(runtime, unique_name(), lines, managed, state.debug)?;
+        state.lines.push(instruction);
+        history.push_rebuilt(state);
+    }
+    Ok(())
+}
+
+/// Lets the user edit a file of the current image, recording the result
+/// as a `COPY` and starting to build its layer from that.
+fn edit_file(
+    runtime: &dyn ContainerRuntime,
+    scratch: &ScratchContainers,
+    managed: &ManagedContext,
+    history: &mut History,
+    path: &str,
+) -> Result<Option<PendingImage>> {
+    let copy = edit::edit(runtime, scratch, history.current(), managed, path)?;
+    match copy {
+        Some((copy, change)) => {
+            let building = build_layer(runtime, managed, history, copy, vec![change])?;
+            Ok(Some(building))
+        }
+        None => {
+            println!("No changes to {}", path);
+            Ok(None)
+        }
+    }
+}
+
+/// Copies a host file or directory into the managed context,
+/// then records and starts building a `COPY` of it to `dest`.
+fn copy_file(
+    runtime: &dyn ContainerRuntime,
+    managed: &ManagedContext,
+    history: &mut History,
+    flags: Vec<String>,
+    source: &str,
+    dest: String,
+) -> Result<PendingImage> {
+    let mode = match flags.iter().find(|flag| flag.starts_with("--chmod=")) {
+        Some(flag) => Some(parse_mode(&flag["--chmod=".len()..])?),
+        None => None,
+    };
+    let source = managed.add(Path::new(source), mode).map_err(|err| match err {
+        Error::Io(ref io) if io.kind() == std::io::ErrorKind::NotFound => {
+            Error::BadArgument(format!("copy: no such file: {}", source))
+        }
+        err => err,
+    })?;
+    // Where each file lands, as `COPY` would put it.
+    let to = in_container(history.current(), &dest);
+    let changes = managed
+        .file_sizes(&source)?
+        .into_iter()
+        .map(|(file, size)| {
+            let path = match file.as_str() {
+                "" if to.ends_with('/') => format!("{}{}", to, source_name(&source)),
+                "" => to.clone(),
+                file => format!("{}/{}", to.trim_end_matches('/'), file),
+            };
+            FileChange::new(&path, ChangeKind::Added, Some(size))
+        })
+        .collect();
+    let copy = Instruction::Copy {
+        flags,
+        sources: vec![source],
+        dest,
+        heredocs: vec![],
+    };
+    build_layer(runtime, managed, history, copy, changes)
+}
+
+/// Records an instruction that has no command to run by building its layer.
+/// A `COPY` of one host path is copied in as `copy` would.
+fn build_instruction(
+    runtime: &dyn ContainerRuntime,
+    managed: &ManagedContext,
+    history: &mut History,
+    instruction: Instruction,
+) -> Result<PendingImage> {
+    match instruction {
+        Instruction::Copy {
+            flags,
+            sources,
+            dest,
+            heredocs,
+        } if sources.len() == 1
+            && heredocs.is_empty()
+            && !flags.iter().any(|flag| flag.starts_with("--from=")) =>
+        {
+            copy_file(runtime, managed, history, flags, &sources[0], dest)
+        }
+        instruction => build_layer(runtime, managed, history, instruction, vec![]),
+    }
+}
+
+fn source_name(source: &str) -> &str {
+    source.rsplit('/').next().unwrap_or(source)
+}
+
+fn parse_mode(mode: &str) -> Result<u32> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| Error::BadArgument(format!("copy: not an octal mode: {}", mode)))
+}
+
+/// Records `instruction`, which makes `changes`, and starts building its
+/// layer on top of the current image. Its state has the image once it is built.
+fn build_layer(
+    runtime: &dyn ContainerRuntime,
+    managed: &ManagedContext,
+    history: &mut History,
+    instruction: Instruction,
+    changes: Vec<FileChange>,
+) -> Result<PendingImage> {
+    let mut state = history.current().clone();
+    println!("Building: {}", instruction);
+    let lines = vec![Instruction::from_image(&state.image_name), instruction.clone()];
+    let build = build_image(runtime, unique_name(), lines, managed, state.debug)?;
+    state.lines.push(instruction);
+    let layer = state.lines.len() - 1;
+    state.layer_changes.insert(layer, changes);
+    history.push(state);
+    Ok(PendingImage {
+        layer,
+        image: build,
+    })
+}
+
+/// Whether a command is recorded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Recording {
+    /// If it succeeded and changed state.
+    Auto,
+    /// `!!cmd`: even if it failed or changed nothing.
+    Always,
+    /// `?cmd`: whatever it did is thrown away.
+    Never,
+}
+
+impl Recording {
+    /// What the line's prefix asks for, and the command without it.
+    pub fn of(line: &str) -> (Recording, &str) {
+        let line = line.trim();
+        if let Some(rest) = line.strip_prefix("!!") {
+            (Recording::Always, rest.trim_start())
+        } else if let Some(rest) = line.strip_prefix('?') {
+            (Recording::Never, rest.trim_start())
+        } else {
+            (Recording::Auto, line)
+        }
     }
 }
 
@@ -254,179 +889,412 @@ pub enum LineResult {
     Exit,
     NoOp(String), // E.g. print state...
     State(State, String),
+    /// The command exited with this status (-1 if docker couldn't say),
+    /// so nothing it did was recorded.
+    Failed(i64, String),
+    /// The command was run with `?`, so nothing it did was recorded.
+    Tried(String),
+    /// Go back before the last change, or remove the given layer.
+    Undo(Option<usize>),
+    Redo,
+    /// Start a named branch here.
+    Checkpoint(String),
+    /// Switch to a named branch.
+    Checkout(String),
+    /// List the branches.
+    Branches,
+    /// Edit the file at this (absolute) path.
+    Edit(String),
+    /// Copy a host file or directory into the image.
+    Copy {
+        /// `--chown`/`--chmod`, as for `COPY`.
+        flags: Vec<String>,
+        source: String,
+        dest: String,
+    },
 }
 
 pub fn parse_line(
     mut line: &str,
     state: &State,
-    docker: &Docker,
-) -> Result<(LineResult, Option<FutureImage>)> {
-    assert_eq!(state.lines[0][0], "FROM");
+    runtime: &dyn ContainerRuntime,
+    scratch: &ScratchContainers,
+) -> Result<(LineResult, Option<Build>)> {
+    assert_eq!(state.lines[0].keyword(), "FROM");
     line = line.trim();
     match line {
         "" => Ok((LineResult::NoOp(String::new()), None)),
-        "exit" => {
-            println!("Dockerfile of session:");
-            print_dockerfile(&state.lines);
-            Ok((LineResult::Exit, None))
-        }
+        "exit" => Ok((LineResult::Exit, None)),
+        "branches" => Ok((LineResult::Branches, None)),
+        _ if line.starts_with("checkpoint ") => Ok((
+            LineResult::Checkpoint(line["checkpoint ".len()..].trim().to_owned()),
+            None,
+        )),
+        _ if line.starts_with("checkout ") => Ok((
+            LineResult::Checkout(line["checkout ".len()..].trim().to_owned()),
+            None,
+        )),
         "debug" => {
             let mut state = state.clone();
             state.debug = !state.debug;
             Ok((LineResult::State(state, String::new()), None))
         }
-        // TODO: undo 3 should remove 3rd item.
-        // Replay breaks - fix then type continue.
-        "undo" => {
+        "timeout" => {
+            match state.timeout {
+                Some(timeout) => println!("Commands are stopped after {}s", timeout.as_secs()),
+                None => println!("Commands can run for as long as they like"),
+            }
+            Ok((LineResult::NoOp(String::new()), None))
+        }
+        // With a command it is timeout(1).
+        _ if line.starts_with("timeout ")
+            && timeout_setting(&line["timeout ".len()..]).is_some() =>
+        {
             let mut state = state.clone();
-            let item = state.lines.pop();
-            println!("Undone: {:?}", item);
+            state.timeout = timeout_setting(&line["timeout ".len()..]).unwrap();
             Ok((LineResult::State(state, String::new()), None))
         }
+        "ignore" => {
+            let status = if state.ignore.enabled { "on" } else { "off" };
+            println!("Ignoring changes to these paths is {}:", status);
+            for pattern in &state.ignore.patterns {
+                println!("  {}", pattern);
+            }
+            Ok((LineResult::NoOp(String::new()), None))
+        }
+        "ignore on" | "ignore off" => {
+            let mut state = state.clone();
+            state.ignore.enabled = line == "ignore on";
+            Ok((LineResult::State(state, String::new()), None))
+        }
+        "undo" => Ok((LineResult::Undo(None), None)),
+        "redo" => Ok((LineResult::Redo, None)),
+        _ if line.starts_with("undo ") => {
+            let arg = line["undo ".len()..].trim();
+            let layer = arg
+                .parse::<usize>()
+                .map_err(|_| Error::BadArgument(format!("undo: not a layer number: {}", arg)))?;
+            if layer == 0 || layer >= state.lines.len() {
+                return Err(Error::BadArgument(format!(
+                    "undo: no layer {} (see `layers`)",
+                    layer
+                )));
+            }
+            Ok((LineResult::Undo(Some(layer)), None))
+        }
         "layers" => {
             print_layers(&state.lines);
             Ok((LineResult::NoOp(String::new()), None))
         }
+        _ if line.starts_with("edit ") => {
+            let path = in_container(state, line["edit ".len()..].trim());
+            Ok((LineResult::Edit(path), None))
+        }
+        _ if line.starts_with("get ") => {
+            let args: Vec<&str> = line["get ".len()..].split_whitespace().collect();
+            let (path, to) = match args[..] {
+                [path, to] => (in_container(state, path), Path::new(to)),
+                _ => {
+                    return Err(Error::BadArgument(
+                        "usage: get <container-path> <host-path>".to_owned(),
+                    ))
+                }
+            };
+            let written = get::get(runtime, scratch, state, &path, to)?;
+            println!("Copied {} to {}", path, written.display());
+            Ok((LineResult::NoOp(String::new()), None))
+        }
+        _ if line.starts_with("copy ") => {
+            let copy = parse_copy(&line["copy ".len()..])?;
+            Ok((copy, None))
+        }
+        "diff" => {
+            diff::show(state, &[])?;
+            Ok((LineResult::NoOp(String::new()), None))
+        }
+        // Otherwise it is diff(1).
+        _ if line.starts_with("diff ") && layer_numbers(&line["diff ".len()..]).is_some() => {
+            diff::show(state, &layer_numbers(&line["diff ".len()..]).unwrap())?;
+            Ok((LineResult::NoOp(String::new()), None))
+        }
         "image" => {
             println!("image name {}", &state.image_name);
             Ok((LineResult::NoOp(String::new()), None))
         }
         _ => {
+            let (recording, line) = Recording::of(line);
+
             let initial_state = state;
             let mut state = initial_state.clone();
-            if line.starts_with("cd ") || line == "cd" {
-                state.lines.push(vec![
-                    "RUN".to_owned(),
-                    (line.to_string() + " ; pwd").to_owned(),
-                ]);
-                let exec_results = execute_command(&docker, &state)?;
-                state.lines.pop();
-                //what do you tell it to build
-                if state.debug {
-                    println!("DIR SET TO {:?}", exec_results.output.trim());
+            state.lines.push(Instruction::run(line));
+            let exec_results = execute_command(runtime, scratch, &state, line)?;
+            let output = exec_results.output();
+            if state.debug {
+                println!(
+                    "exit code {:?} after {:?}",
+                    exec_results.exit_code, exec_results.duration
+                );
+            }
+
+            if !exec_results.succeeded() && recording != Recording::Always {
+                if exec_results.taken {
+                    runtime.remove_container(&exec_results.container_name)?;
                 }
-                let new_pwd = exec_results.output.trim().to_owned();
-                if new_pwd.lines().count() == 1 {
-                    // Inefficient to have two WORKDIR statements in a row...
-                    if state.lines.last().unwrap()[0] == "WORKDIR" {
-                        state.lines.pop();
-                    }
+                // As the shell has it when it can't tell.
+                let status = exec_results.exit_code.unwrap_or(-1);
+                return Ok((LineResult::Failed(status, output), None));
+            }
 
-                    state.pwd = new_pwd;
-                    state
-                        .lines
-                        .push(vec!["WORKDIR".to_owned(), state.pwd.clone()]);
-                    let image_name = build_image(
-                        exec_results.container_name,
-                        state.lines.clone(),
-                        state.debug,
-                    )
-                        .boxed();
-                    state.image_name = "Pending".to_owned(); //todo enum.
-                    Ok((
-                        LineResult::State(state, exec_results.output),
-                        Some(image_name),
-                    ))
-                } else {
-                    Err(())
-                } //TODO return exec results..
+            if recording == Recording::Never {
+                if exec_results.taken {
+                    runtime.remove_container(&exec_results.container_name)?;
+                }
+                return Ok((LineResult::Tried(output), None));
+            }
+
+            let recorded = exec_results.state_change || recording == Recording::Always;
+            if recorded {
+                let run = state.lines.len() - 1;
+                state.layer_changes.insert(run, exec_results.changes.clone());
             } else {
-                state.lines.push(vec!["RUN".to_owned(), line.to_owned()]);
-                let exec_result = execute_command(&docker, &state);
-
-                match exec_result {
-                    Ok(ExecResults {
-                        state_change: true,
-                        container_name,
-                        output,
-                        ..
-                    }) => {
-                        let image_name =
-                            build_image(container_name, state.lines.clone(), state.debug).boxed();
-
-                        Ok((LineResult::State(state, output), Some(image_name)))
-                    }
-                    Ok(ExecResults {
-                        state_change: false,
-                        output,
-                        ..
-                    }) => {
-                        let removed = state.lines.remove(state.lines.len() - 1);
+                let removed = state.lines.remove(state.lines.len() - 1);
+                if state.debug {
+                    println!("No state change, removed {}. State={:?}", removed, state);
+                }
+            }
+
+            // Wherever the shell was left is where the next command starts.
+            let moved = match exec_results.dirs {
+                Some(dirs) => {
+                    let moved = dirs.pwd != state.pwd;
+                    if moved {
                         if state.debug {
-                            println!("No state change, removed {:?}. State={:?}", removed, state);
+                            println!("DIR SET TO {:?}", dirs.pwd);
                         }
-                        Ok((LineResult::NoOp(output), None))
+                        // Inefficient to have two WORKDIR statements in a row...
+                        if let Instruction::Workdir(_) = state.last_instruction() {
+                            state.lines.pop();
+                        }
+                        state.lines.push(Instruction::workdir(&dirs.pwd));
+                        state.pwd = dirs.pwd;
                     }
-                    Err(()) => Err(()),
+                    state.oldpwd = dirs.oldpwd;
+                    state.dir_stack = dirs.stack;
+                    moved
                 }
+                None => false,
+            };
+
+            let (container, taken) = (exec_results.container_name, exec_results.taken);
+            let image_name = if recorded || moved {
+                let kept = initial_state
+                    .lines
+                    .iter()
+                    .zip(&state.lines)
+                    .take_while(|(before, after)| before == after)
+                    .count();
+                let (container, remove) = if taken && !recorded {
+                    // Only the directory changed: what it did in ignored places stays out.
+                    runtime.remove_container(&container)?;
+                    (scratch.get(runtime, initial_state)?, false)
+                } else {
+                    // Otherwise it is still the scratch container.
+                    (container, taken)
+                };
+                Some(commit_layer(
+                    runtime,
+                    container,
+                    state.lines[kept..].to_vec(),
+                    remove,
+                )?)
+            } else {
+                if taken {
+                    runtime.remove_container(&container)?;
+                }
+                None
+            };
+
+            // Exported variables only last as long as the shell, so record them.
+            if !exec_results.env_changes.is_empty() {
+                state.lines.push(Instruction::Env(env::escape(exec_results.env_changes)));
+            }
+            if state == *initial_state {
+                return Ok((LineResult::NoOp(output), None));
             }
+            Ok((LineResult::State(state, output), image_name))
         }
     }
 }
 
-async fn build_image(
-    image_name: String,
-    command_lines: Vec<Vec<String>>,
-    debug: bool,
-) -> Box<String> {
-    assert_eq!(command_lines[0][0], "FROM");
-    if debug {
-        println!("building img {} as {:?}", &image_name, &command_lines)
+/// One or two layer numbers, e.g. `3` or `1 4`.
+fn layer_numbers(args: &str) -> Option<Vec<usize>> {
+    let numbers: Vec<usize> = args
+        .split_whitespace()
+        .map(|arg| arg.parse().ok())
+        .collect::<Option<_>>()?;
+    Some(numbers).filter(|numbers| numbers.len() == 1 || numbers.len() == 2)
+}
+
+/// Built-ins that only look at the history.
+fn reads_history_only(line: &str) -> bool {
+    match line.trim() {
+        "" | "layers" | "branches" | "diff" | "ignore" | "timeout" => true,
+        line => line.starts_with("diff ") && layer_numbers(&line["diff ".len()..]).is_some(),
     }
-    let docker = Docker::connect_with_defaults().unwrap();
-    {
-        let mut dockerfile = File::create("Dockerfile").unwrap();
-        let lines: Vec<String> = command_lines.iter().map(|args| args.join(" ")).collect();
-        dockerfile.write_all(lines.join("\n").as_bytes()).unwrap();
+}
+
+/// `off`, or a (non-zero) number of seconds.
+fn timeout_setting(arg: &str) -> Option<Option<Duration>> {
+    match arg.trim() {
+        "off" => Some(None),
+        secs => secs
+            .parse()
+            .ok()
+            .filter(|&secs| secs > 0)
+            .map(|secs| Some(Duration::from_secs(secs))),
     }
-    // Create tar file
-    {
-        let tar_file = File::create("image.tar").unwrap();
-        let mut a = Builder::new(tar_file);
-        a.append_path("Dockerfile").unwrap();
+}
+
+/// `path` in the container, relative to the working directory unless absolute.
+fn in_container(state: &State, path: &str) -> String {
+    if path.starts_with('/') {
+        path.to_owned()
+    } else {
+        format!("{}/{}", state.pwd.trim_end_matches('/'), path)
     }
-    let options = ContainerBuildOptions {
-        t: vec![image_name.to_owned()],
-        ..ContainerBuildOptions::default()
+}
+
+/// `[--chown=user:group] [--chmod=mode] <host-path> <container-path>`
+fn parse_copy(args: &str) -> Result<LineResult> {
+    let usage = || {
+        Error::BadArgument(
+            "usage: copy [--chown=user:group] [--chmod=mode] <host-path> <container-path>"
+                .to_owned(),
+        )
     };
-    let res = docker.build_image(options, Path::new("image.tar")).unwrap();
+    let (flags, paths): (Vec<&str>, Vec<&str>) =
+        args.split_whitespace().partition(|arg| arg.starts_with("--"));
+    if flags
+        .iter()
+        .any(|flag| !flag.starts_with("--chown=") && !flag.starts_with("--chmod="))
+    {
+        return Err(usage());
+    }
+    match paths[..] {
+        [source, dest] => Ok(LineResult::Copy {
+            flags: flags.into_iter().map(|flag| flag.to_owned()).collect(),
+            source: source.to_owned(),
+            dest: dest.to_owned(),
+        }),
+        _ => Err(usage()),
+    }
+}
 
-    for line in BufReader::new(res).lines() {
-        let buf = line.unwrap();
-        if debug {
-            println!("{}", &buf);
+/// Starts recording the container a line ran in as the layer for
+/// `instructions`, removing it after if it is no longer a clean scratch container.
+fn commit_layer(
+    runtime: &dyn ContainerRuntime,
+    container_name: String,
+    instructions: Vec<Instruction>,
+    remove: bool,
+) -> Result<Build> {
+    let changes = instructions
+        .iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::Workdir(dir) => Some(format!("WORKDIR {}", dir)),
+            _ => None,
+        })
+        .collect();
+    let comment: Vec<String> = instructions.iter().map(|i| i.to_string()).collect();
+    let commit = Commit {
+        tag: unique_name(),
+        comment: comment.join("; "),
+        changes,
+        remove,
+    };
+    match runtime.commit_container(&container_name, &commit) {
+        Ok(committing) => {
+            let step = format!("committing {}", commit.comment);
+            Ok(Build::run(commit.tag, step, committing))
+        }
+        Err(err) => {
+            if remove {
+                let _ = runtime.remove_container(&container_name);
+            }
+            Err(err)
         }
     }
+}
+
+/// Starts building the recipe from scratch, for when there is no container to commit.
+fn build_image(
+    runtime: &dyn ContainerRuntime,
+    image_name: String,
+    command_lines: Vec<Instruction>,
+    managed: &ManagedContext,
+    debug: bool,
+) -> Result<Build> {
     if debug {
-        println!("built image {}", &image_name);
+        println!("building img {} as {:?}", &image_name, &command_lines)
     }
+    // Only BuildKit knows `COPY --chmod`, but the managed context's
+    // copies already have the mode it asks for.
+    let command_lines: Vec<Instruction> = command_lines
+        .into_iter()
+        .map(|mut line| {
+            if let Instruction::Copy { ref mut flags, .. } = line {
+                flags.retain(|flag| !flag.starts_with("--chmod="));
+            }
+            line
+        })
+        .collect();
+    // Removed again when this returns, however it returns.
+    let context = BuildContext::new(&to_dockerfile(&command_lines), managed)?;
+    // Docker has the whole context by the time it answers.
+    let res = runtime.build_image(&image_name, context.tar())?;
+    Ok(Build::follow(image_name, res, debug))
+}
 
-    Box::new(image_name.to_owned())
+/// Builds the recipe from scratch, waiting for it.
+fn build_now(
+    runtime: &dyn ContainerRuntime,
+    image_name: String,
+    command_lines: Vec<Instruction>,
+    managed: &ManagedContext,
+    debug: bool,
+) -> Result<String> {
+    let mut build = build_image(runtime, image_name, command_lines, managed, debug)?;
+    let progress = build.progress();
+    progress::wait(&mut build, Some(&progress), "building")
 }
 
 mod tests {
     #[test]
     fn initial_command() {
-        let docker = dockworker::Docker::connect_with_defaults().unwrap();
+        let docker = super::DockerRuntime::connect_with_defaults().unwrap();
         let state = super::State {
             lines: vec![
-                vec!["FROM".to_owned(), "alpine:edge".to_owned()],
-                vec![
-                    "RUN".to_owned(),
-                    "/bin/echo".to_owned(),
-                    "Hello World".to_owned(),
-                ],
+                super::Instruction::from_image("alpine:edge"),
+                super::Instruction::run("/bin/echo Hello World"),
             ],
             debug: true,
             tty: false,
             image_name: "alpine:edge".to_owned(),
             pwd: "/bin".to_owned(),
+            oldpwd: String::new(),
+            dir_stack: vec![],
+            layer_changes: Default::default(),
+            ignore: Default::default(),
+            timeout: None,
             shell: "/bin/sh".to_owned(),
+            editor: None,
         };
 
-        let exec_results: super::ExecResults = super::execute_command(&docker, &state).unwrap();
+        let scratch = super::ScratchContainers::new();
+        let exec_results: super::exec::ExecResults = super::execute_command(&docker, &scratch, &state, "/bin/echo Hello World").unwrap();
+        scratch.remove_all(&docker).unwrap();
 
-        println!("{}", exec_results.output);
-        assert!(exec_results.output.contains("Hello World"));
+        println!("{}", exec_results.stdout);
+        assert!(exec_results.stdout.contains("Hello World"));
     }
 }
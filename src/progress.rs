@@ -0,0 +1,269 @@
+//! Docker builds and commits followed on a worker thread, so the prompt can
+//! come back while they run, with a build's JSON stream made into progress
+//! and errors.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+use futures::channel::oneshot;
+use futures::task::{waker_ref, ArcWake, Context, Poll};
+use futures::Future;
+use serde_json::Value;
+
+use super::error::{Error, Result};
+
+/// How often the spinner turns.
+const SPIN_MS: u64 = 100;
+const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+
+/// One message of a build's JSON stream.
+#[derive(Debug, PartialEq)]
+pub(crate) enum BuildMessage {
+    /// Starting an instruction, e.g. `Step 2/3 : RUN make`.
+    Step(String),
+    /// What a step printed.
+    Output(String),
+    /// Docker's own remarks, e.g. on pulling the base image.
+    Status(String),
+    Error(String),
+}
+
+impl BuildMessage {
+    /// None for messages only docker needs, like the built image's id.
+    pub(crate) fn parse(line: &str) -> Option<BuildMessage> {
+        let json: Value = match serde_json::from_str(line) {
+            Ok(json) => json,
+            // Not JSON, so just output.
+            Err(_) => return Some(BuildMessage::Output(line.to_owned())),
+        };
+        let text = |key: &str| json.get(key).and_then(Value::as_str);
+        if let Some(error) = text("error") {
+            let detail = json.pointer("/errorDetail/message").and_then(Value::as_str);
+            return Some(BuildMessage::Error(detail.unwrap_or(error).to_owned()));
+        }
+        if let Some(stream) = text("stream") {
+            let stream = stream.trim_end();
+            if stream.starts_with("Step ") {
+                return Some(BuildMessage::Step(stream.to_owned()));
+            }
+            if stream.starts_with(" ---> ") || stream.starts_with("Removing intermediate") {
+                return Some(BuildMessage::Status(stream.to_owned()));
+            }
+            return Some(BuildMessage::Output(stream.to_owned())).filter(|_| !stream.is_empty());
+        }
+        text("status").map(|status| match text("progress") {
+            Some(progress) => BuildMessage::Status(format!("{} {}", status, progress)),
+            None => BuildMessage::Status(status.to_owned()),
+        })
+    }
+}
+
+/// What a build is up to, shared with the thread following it.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Progress(Arc<Mutex<Status>>);
+
+#[derive(Debug, Default)]
+struct Status {
+    step: String,
+    /// What the step has printed so far.
+    output: String,
+    /// The first error docker reported.
+    error: Option<String>,
+    done: bool,
+}
+
+impl Progress {
+    /// The step it is on, e.g. `Step 2/3 : RUN make`.
+    pub(crate) fn step(&self) -> String {
+        self.0.lock().unwrap().step.clone()
+    }
+
+    pub(crate) fn done(&self) -> bool {
+        self.0.lock().unwrap().done
+    }
+}
+
+/// An image docker is building or committing. Resolves to its name once that is over.
+pub struct Build {
+    image: String,
+    progress: Progress,
+    finished: oneshot::Receiver<Result<()>>,
+}
+
+impl Build {
+    /// Follows `stream`, the response to building `image`, on its own thread.
+    /// In debug mode each message is printed as it comes.
+    pub(crate) fn follow(image: String, stream: Box<dyn Read + Send>, debug: bool) -> Build {
+        let progress = Progress::default();
+        let (sender, finished) = oneshot::channel();
+        let status = progress.0.clone();
+        thread::spawn(move || {
+            let mut read = Ok(());
+            for line in BufReader::new(stream).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(err) => {
+                        read = Err(err);
+                        break;
+                    }
+                };
+                if debug {
+                    println!("{}", line);
+                }
+                let mut now = status.lock().unwrap();
+                match BuildMessage::parse(&line) {
+                    Some(BuildMessage::Step(step)) => {
+                        now.step = step;
+                        now.output.clear();
+                    }
+                    Some(BuildMessage::Output(output)) => {
+                        now.output.push_str(&output);
+                        now.output.push('\n');
+                    }
+                    Some(BuildMessage::Error(error)) => {
+                        now.error.get_or_insert(error);
+                    }
+                    _ => {}
+                }
+            }
+            status.lock().unwrap().done = true;
+            // Nobody is waiting if the session has ended.
+            let _ = sender.send(read.map_err(Error::from));
+        });
+        Build {
+            image,
+            progress,
+            finished,
+        }
+    }
+
+    /// Runs `job`, which makes `image` in one go, on its own thread.
+    /// `step` is what it is up to meanwhile.
+    pub(crate) fn run<F>(image: String, step: String, job: F) -> Build
+    where
+        F: FnOnce() -> Result<String> + Send + 'static,
+    {
+        let progress = Progress::default();
+        progress.0.lock().unwrap().step = step;
+        let (sender, finished) = oneshot::channel();
+        let status = progress.0.clone();
+        thread::spawn(move || {
+            let made = job().map(|_| ());
+            status.lock().unwrap().done = true;
+            let _ = sender.send(made);
+        });
+        Build {
+            image,
+            progress,
+            finished,
+        }
+    }
+
+    pub(crate) fn progress(&self) -> Progress {
+        self.progress.clone()
+    }
+}
+
+impl Future for Build {
+    type Output = Result<String>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<String>> {
+        match Pin::new(&mut self.finished).poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(Err(err))) => Poll::Ready(Err(err)),
+            Poll::Ready(Ok(Ok(()))) => {
+                let status = self.progress.0.lock().unwrap();
+                Poll::Ready(match &status.error {
+                    Some(message) => Err(Error::BuildFailed {
+                        image: self.image.clone(),
+                        step: status.step.clone(),
+                        output: status.output.clone(),
+                        message: message.clone(),
+                    }),
+                    None => Ok(self.image.clone()),
+                })
+            }
+            Poll::Ready(Err(oneshot::Canceled)) => panic!("build thread panicked"),
+        }
+    }
+}
+
+/// Wakes the thread waiting for an image once it is made.
+struct Unpark(Thread);
+
+impl ArcWake for Unpark {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.unpark();
+    }
+}
+
+/// Waits for `image`, with a spinner saying what its build is doing.
+pub(crate) fn wait<F>(image: &mut F, progress: Option<&Progress>, what: &str) -> Result<String>
+where
+    F: Future<Output = Result<String>> + Unpin,
+{
+    let unpark = Arc::new(Unpark(thread::current()));
+    let waker = waker_ref(&unpark);
+    let mut cx = Context::from_waker(&waker);
+    let mut spun = 0;
+    loop {
+        if let Poll::Ready(result) = Pin::new(&mut *image).poll(&mut cx) {
+            if spun > 0 {
+                print!("\r\x1b[K");
+                io::stdout().flush()?;
+            }
+            return result;
+        }
+        let parked = Instant::now();
+        thread::park_timeout(Duration::from_millis(SPIN_MS));
+        // Woken early, most likely as it is done, so no need to spin.
+        if parked.elapsed() < Duration::from_millis(SPIN_MS) {
+            continue;
+        }
+        let step = progress.map(Progress::step).unwrap_or_default();
+        print!(
+            "\r{} {} {}\x1b[K",
+            SPINNER[spun % SPINNER.len()],
+            what,
+            step
+        );
+        io::stdout().flush()?;
+        spun += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BuildMessage::{self, *};
+
+    #[test]
+    fn build_messages_are_parsed() {
+        let parse = BuildMessage::parse;
+        assert_eq!(
+            parse(r#"{"stream":"Step 2/3 : RUN make\n"}"#),
+            Some(Step("Step 2/3 : RUN make".to_owned()))
+        );
+        assert_eq!(
+            parse(r#"{"stream":"make: *** No targets.  Stop.\n"}"#),
+            Some(Output("make: *** No targets.  Stop.".to_owned()))
+        );
+        assert_eq!(
+            parse(r#"{"stream":" ---> Running in 1234\n"}"#),
+            Some(Status(" ---> Running in 1234".to_owned()))
+        );
+        assert_eq!(parse(r#"{"stream":"\n"}"#), None);
+        assert_eq!(
+            parse(r#"{"status":"Downloading","progress":"[=>  ] 1MB/2MB","id":"1"}"#),
+            Some(Status("Downloading [=>  ] 1MB/2MB".to_owned()))
+        );
+        assert_eq!(
+            parse(r#"{"errorDetail":{"code":1,"message":"exit code: 1"},"error":"failed"}"#),
+            Some(Error("exit code: 1".to_owned()))
+        );
+        assert_eq!(parse(r#"{"aux":{"ID":"sha256:1234"}}"#), None);
+        assert_eq!(parse("plain"), Some(Output("plain".to_owned())));
+    }
+}
@@ -0,0 +1,363 @@
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use dockworker::container::{AttachContainer, AttachResponse, Container, ContainerFilters};
+use dockworker::*;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::docker_api;
+use super::error::{Error, Result};
+
+/// What to run in a new container.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContainerSpec {
+    pub image: String,
+    /// Full command line, e.g. `["/bin/sh", "-c", "ls"]`.
+    pub cmd: Vec<String>,
+    /// `NAME=value` pairs, on top of the image's own.
+    pub env: Vec<String>,
+    pub tty: bool,
+}
+
+/// Output streams of an attached container.
+pub enum Attached {
+    /// In tty mode docker sends everything down one raw stream,
+    /// and takes keystrokes on another.
+    Tty {
+        output: Box<dyn Read + Send>,
+        input: Box<dyn Write + Send>,
+    },
+    /// Otherwise stdout and stderr are multiplexed separately.
+    Split {
+        stdout: Box<dyn Read + Send>,
+        stderr: Box<dyn Read + Send>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ChangeKind {
+    Modified,
+    Added,
+    Deleted,
+}
+
+/// One entry of `docker diff`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub path: String,
+    pub kind: ChangeKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageInfo {
+    pub id: String,
+}
+
+/// How to record a container as a layer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Commit {
+    /// Name for the new image.
+    pub tag: String,
+    /// The instruction the layer stands for, e.g. `RUN apk add curl`.
+    pub comment: String,
+    /// Dockerfile instructions to apply to the image config, e.g. `WORKDIR /src`.
+    pub changes: Vec<String>,
+    /// Whether the container is removed once it has been committed.
+    pub remove: bool,
+}
+
+/// Makes a commit when called, on whichever thread calls it. Returns the new image's name.
+pub type Committing = Box<dyn FnOnce() -> Result<String> + Send>;
+
+/// Everything dockershell needs from a container engine.
+///
+/// Containers are addressed by the name given to `create_container`.
+pub trait ContainerRuntime {
+    /// Creates (but does not start) a container. Returns its id.
+    fn create_container(&self, name: &str, spec: &ContainerSpec) -> Result<String>;
+    fn attach_container(&self, name: &str, tty: bool) -> Result<Attached>;
+    fn start_container(&self, name: &str) -> Result<()>;
+    /// Sets up `cmd` to run in a running container. Returns the exec's id.
+    fn create_exec(&self, container: &str, cmd: &[String], tty: bool) -> Result<String>;
+    /// Runs an exec created by `create_exec`, attached to its output.
+    fn start_exec(&self, id: &str, tty: bool) -> Result<Attached>;
+    /// How an exec exited, or None if it hasn't yet.
+    fn exec_exit_code(&self, id: &str) -> Result<Option<i64>>;
+    /// Sets the size of a running tty exec's terminal.
+    fn resize_exec(&self, id: &str, rows: u16, cols: u16) -> Result<()>;
+    /// Filesystem changes of a container relative to its image.
+    fn filesystem_changes(&self, name: &str) -> Result<Vec<Change>>;
+    /// Sizes of the regular files among `paths` in a running container, None for the rest.
+    fn file_sizes(&self, container: &str, paths: &[String]) -> Result<Vec<Option<u64>>>;
+    /// Names in a directory of a running container, those of directories ending
+    /// in `/`. Empty if it is not a directory.
+    fn list_dir(&self, container: &str, dir: &str) -> Result<Vec<String>>;
+    /// Names of the executables on a running container's `PATH`.
+    fn executables(&self, container: &str) -> Result<Vec<String>>;
+    fn remove_container(&self, name: &str) -> Result<()>;
+    /// A tar of the file or directory at `path` in a container,
+    /// named after its last component.
+    fn download_path(&self, container: &str, path: &str) -> Result<Box<dyn Read>>;
+    /// Builds the tar'd context at `context`, tagging it `tag`.
+    /// Returns docker's JSON progress stream.
+    fn build_image(&self, tag: &str, context: &Path) -> Result<Box<dyn Read + Send>>;
+    /// Err if the image does not exist.
+    fn inspect_image(&self, name: &str) -> Result<ImageInfo>;
+    /// Gets ready to turn a container into an image, keeping its image's `CMD`
+    /// and environment rather than what it was started with. Nothing is
+    /// committed until the returned job is run.
+    fn commit_container(&self, name: &str, commit: &Commit) -> Result<Committing>;
+}
+
+/// Prints the size of each regular file argument, or `-`.
+const SIZES_SCRIPT: &str = r#"for p; do
+    if [ -f "$p" ] && [ ! -L "$p" ]; then stat -c %s "$p" 2>/dev/null || echo -; else echo -; fi
+done"#;
+
+/// Lists the directory argument, dotfiles and all, marking directories with a `/`.
+const LIST_SCRIPT: &str = r#"cd "$1" 2>/dev/null || exit 0
+for f in * .[!.]* ..?*; do
+    if [ -d "$f" ]; then echo "$f/"; elif [ -e "$f" ] || [ -L "$f" ]; then echo "$f"; fi
+done"#;
+
+/// Lists the executable files in each directory on `$PATH`.
+const EXECUTABLES_SCRIPT: &str = r#"IFS=:
+for dir in $PATH; do
+    for f in "$dir"/*; do if [ -f "$f" ] && [ -x "$f" ]; then echo "${f##*/}"; fi; done
+done"#;
+
+/// The real thing: talks to a docker daemon via dockworker.
+pub struct DockerRuntime {
+    docker: Docker,
+}
+
+impl DockerRuntime {
+    pub fn connect_with_defaults() -> Result<DockerRuntime> {
+        let docker = Docker::connect_with_defaults()
+            .map_err(|err| Error::DaemonConnection(err.to_string()))?;
+        Ok(DockerRuntime { docker })
+    }
+
+    fn find_container(&self, name: &str) -> Result<Container> {
+        let search_name = String::from("/") + name;
+        let mut filters = ContainerFilters::new();
+        filters.name(&search_name);
+
+        let mut res = self
+            .docker
+            .list_containers(Some(true), None, None, filters)?;
+        if res.is_empty() {
+            return Err(Error::ContainerNotFound(name.to_owned()));
+        }
+        Ok(res.remove(0))
+    }
+
+    /// Runs `cmd` in a running container, returning the lines it printed.
+    fn output_lines(&self, container: &str, cmd: &[&str]) -> Result<Vec<String>> {
+        let cmd: Vec<String> = cmd.iter().map(|arg| arg.to_string()).collect();
+        let id = self.create_exec(container, &cmd, false)?;
+        let mut output = String::new();
+        if let Attached::Split { mut stdout, .. } = self.start_exec(&id, false)? {
+            stdout.read_to_string(&mut output)?;
+        }
+        Ok(output.lines().map(|line| line.to_owned()).collect())
+    }
+}
+
+impl ContainerRuntime for DockerRuntime {
+    fn create_container(&self, name: &str, spec: &ContainerSpec) -> Result<String> {
+        let mut host_config = ContainerHostConfig::new();
+        host_config.auto_remove(false);
+
+        let mut create = ContainerCreateOptions::new(&spec.image);
+        create.tty(spec.tty);
+        for arg in &spec.cmd {
+            create.cmd(arg.clone());
+        }
+        for var in &spec.env {
+            create.env(var.clone());
+        }
+        create.host_config(host_config);
+
+        let container = self.docker.create_container(Some(name), &create)?;
+        Ok(container.id)
+    }
+
+    fn attach_container(&self, name: &str, tty: bool) -> Result<Attached> {
+        let res = self
+            .docker
+            .attach_container(name, None, true, true, true, true, true)?;
+        Ok(attached(res, tty))
+    }
+
+    fn start_container(&self, name: &str) -> Result<()> {
+        self.docker.start_container(name).map_err(Error::from)
+    }
+
+    fn create_exec(&self, container: &str, cmd: &[String], tty: bool) -> Result<String> {
+        let mut options = CreateExecOptions::new();
+        options.tty(tty);
+        options.attach_stdin(tty);
+        for arg in cmd {
+            options.cmd(arg.clone());
+        }
+        Ok(self.docker.exec_container(container, &options)?.id)
+    }
+
+    fn start_exec(&self, id: &str, tty: bool) -> Result<Attached> {
+        if tty {
+            // dockworker can't write to an exec, so take the connection over.
+            let path = format!("/exec/{}/start", docker_api::encode(id));
+            let (output, input) =
+                docker_api::upgrade(&path, &json!({ "Detach": false, "Tty": true }))?;
+            return Ok(Attached::Tty { output, input });
+        }
+        let mut options = StartExecOptions::new();
+        options.tty(tty);
+        let res = self.docker.start_exec(id, &options)?;
+        Ok(attached(res, tty))
+    }
+
+    fn exec_exit_code(&self, id: &str) -> Result<Option<i64>> {
+        let info = self.docker.exec_inspect(id)?;
+        Ok(info.ExitCode.filter(|_| !info.Running).map(i64::from))
+    }
+
+    fn resize_exec(&self, id: &str, rows: u16, cols: u16) -> Result<()> {
+        let path = format!(
+            "/exec/{}/resize?h={}&w={}",
+            docker_api::encode(id),
+            rows,
+            cols
+        );
+        docker_api::request("POST", &path, None)?;
+        Ok(())
+    }
+
+    fn filesystem_changes(&self, name: &str) -> Result<Vec<Change>> {
+        let container = self.find_container(name)?;
+        // Docker answers `null` rather than `[]` when nothing changed,
+        // which dockworker fails to parse.
+        let changes = self
+            .docker
+            .filesystem_changes(&container)
+            .unwrap_or_default();
+        Ok(changes
+            .into_iter()
+            .map(|change| Change {
+                path: change.Path,
+                kind: match change.Kind {
+                    0 => ChangeKind::Modified,
+                    1 => ChangeKind::Added,
+                    _ => ChangeKind::Deleted,
+                },
+            })
+            .collect())
+    }
+
+    fn file_sizes(&self, container: &str, paths: &[String]) -> Result<Vec<Option<u64>>> {
+        let mut sizes = Vec::with_capacity(paths.len());
+        // A few at a time, to keep within the limit on a command's length.
+        for chunk in paths.chunks(500) {
+            let mut cmd: Vec<String> =
+                vec!["sh".into(), "-c".into(), SIZES_SCRIPT.into(), "sh".into()];
+            cmd.extend(chunk.iter().cloned());
+            let id = self.create_exec(container, &cmd, false)?;
+            let mut output = String::new();
+            if let Attached::Split { mut stdout, .. } = self.start_exec(&id, false)? {
+                stdout.read_to_string(&mut output)?;
+            }
+            let lines: Vec<&str> = output.lines().collect();
+            if lines.len() != chunk.len() {
+                return Err(Error::Docker(format!(
+                    "expected {} file sizes, got: {}",
+                    chunk.len(),
+                    output
+                )));
+            }
+            sizes.extend(lines.into_iter().map(|line| line.parse().ok()));
+        }
+        Ok(sizes)
+    }
+
+    fn list_dir(&self, container: &str, dir: &str) -> Result<Vec<String>> {
+        self.output_lines(container, &["sh", "-c", LIST_SCRIPT, "sh", dir])
+    }
+
+    fn executables(&self, container: &str) -> Result<Vec<String>> {
+        self.output_lines(container, &["sh", "-c", EXECUTABLES_SCRIPT])
+    }
+
+    fn remove_container(&self, name: &str) -> Result<()> {
+        self.docker
+            .remove_container(name, None, Some(true), None)
+            .map_err(Error::from)
+    }
+
+    fn download_path(&self, container: &str, path: &str) -> Result<Box<dyn Read>> {
+        let archive = self.docker.get_file(container, Path::new(path))?;
+        Ok(archive.into_inner())
+    }
+
+    fn build_image(&self, tag: &str, context: &Path) -> Result<Box<dyn Read + Send>> {
+        let options = ContainerBuildOptions {
+            t: vec![tag.to_owned()],
+            ..ContainerBuildOptions::default()
+        };
+        let res = self.docker.build_image(options, context)?;
+        Ok(Box::new(res))
+    }
+
+    fn inspect_image(&self, name: &str) -> Result<ImageInfo> {
+        let image = self.docker.inspect_image(name)?;
+        Ok(ImageInfo { id: image.Id })
+    }
+
+    fn commit_container(&self, name: &str, commit: &Commit) -> Result<Committing> {
+        let container = self.find_container(name)?;
+        let image = format!("/images/{}/json", docker_api::encode(&container.Image));
+        let mut query = format!(
+            "container={}&repo={}&comment={}",
+            docker_api::encode(name),
+            docker_api::encode(&commit.tag),
+            docker_api::encode(&commit.comment)
+        );
+        for change in &commit.changes {
+            query += &format!("&changes={}", docker_api::encode(change));
+        }
+        let (name, commit) = (name.to_owned(), commit.clone());
+        // The socket calls need nothing of dockworker's, so can go on another thread.
+        Ok(Box::new(move || {
+            let image = docker_api::request("GET", &image, None)?;
+            let config = json!({
+                "Cmd": image["Config"]["Cmd"],
+                "Env": image["Config"]["Env"],
+            });
+            let committed =
+                docker_api::request("POST", &format!("/commit?{}", query), Some(&config));
+            if commit.remove {
+                let path = format!("/containers/{}?force=true", docker_api::encode(&name));
+                docker_api::request("DELETE", &path, None)?;
+            }
+            committed.map(|_| commit.tag)
+        }))
+    }
+}
+
+fn attached(res: AttachResponse, tty: bool) -> Attached {
+    if tty {
+        Attached::Tty {
+            output: Box::new(res.unwrap()),
+            // Attaching this way is read only.
+            input: Box::new(io::sink()),
+        }
+    } else {
+        let cont: AttachContainer = res.into();
+        Attached::Split {
+            stdout: Box::new(cont.stdout),
+            stderr: Box::new(cont.stderr),
+        }
+    }
+}
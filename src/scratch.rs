@@ -0,0 +1,78 @@
+//! Long-lived containers that commands are exec'd in, one per image.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use super::env;
+use super::error::Result;
+use super::exec::unique_name;
+use super::runtime::{ContainerRuntime, ContainerSpec};
+use super::State;
+
+/// What a scratch container runs while it waits for execs.
+const KEEP_ALIVE: &str = "while :; do sleep 3600; done";
+
+/// Running containers, by the image and environment they were started
+/// with, whose filesystems are still identical to that image.
+#[derive(Debug, Default)]
+pub struct ScratchContainers {
+    by_key: RefCell<HashMap<String, String>>,
+}
+
+impl ScratchContainers {
+    pub fn new() -> ScratchContainers {
+        ScratchContainers::default()
+    }
+
+    /// The scratch container for the state's image, started if need be.
+    pub fn get(&self, runtime: &dyn ContainerRuntime, state: &State) -> Result<String> {
+        let env = env::from_instructions(&state.lines);
+        let key = key(&state.image_name, &env);
+        if let Some(name) = self.by_key.borrow().get(&key) {
+            return Ok(name.clone());
+        }
+
+        let name = unique_name();
+        let spec = ContainerSpec {
+            image: state.image_name.clone(),
+            cmd: vec![state.shell.clone(), "-c".to_owned(), KEEP_ALIVE.to_owned()],
+            env,
+            tty: false,
+        };
+        runtime.create_container(&name, &spec)?;
+        if let Err(err) = runtime.start_container(&name) {
+            let _ = runtime.remove_container(&name);
+            return Err(err);
+        }
+        if state.debug {
+            println!(
+                "started scratch container {} for {}",
+                name, state.image_name
+            );
+        }
+        self.by_key.borrow_mut().insert(key, name.clone());
+        Ok(name)
+    }
+
+    /// Stops handing out the state's container, e.g. because a command changed
+    /// it. Whoever called this is now responsible for removing it.
+    pub fn take(&self, state: &State) -> Option<String> {
+        let key = key(&state.image_name, &env::from_instructions(&state.lines));
+        self.by_key.borrow_mut().remove(&key)
+    }
+
+    pub fn remove_all(&self, runtime: &dyn ContainerRuntime) -> Result<()> {
+        let names: Vec<String> = self.by_key.borrow_mut().drain().map(|(_, n)| n).collect();
+        let mut result = Ok(());
+        for name in names {
+            if let Err(err) = runtime.remove_container(&name) {
+                result = Err(err);
+            }
+        }
+        result
+    }
+}
+
+fn key(image: &str, env: &[String]) -> String {
+    format!("{}\n{}", image, env.join("\n"))
+}
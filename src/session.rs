@@ -0,0 +1,150 @@
+//! Sessions saved to disk so they can be picked up again with `--resume`.
+
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use super::context::ManagedContext;
+use super::error::{Error, Result};
+use super::History;
+
+/// Bumped whenever the file layout changes.
+const FORMAT: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct SessionFile {
+    format: u32,
+    history: History,
+}
+
+/// Where a session's history is kept: `$XDG_DATA_HOME/dockershell/sessions/<name>.json`,
+/// with the files its `COPY` instructions need in `<name>.context/`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Session {
+    path: PathBuf,
+}
+
+impl Session {
+    /// A new session in the data dir.
+    pub fn create() -> Result<Session> {
+        Session::create_in(&sessions_dir()?)
+    }
+
+    /// A new session in `dir`, named after the time it was started and the
+    /// process that started it. Its file is created at once, so no other
+    /// session can be given the same name.
+    pub fn create_in(dir: &Path) -> Result<Session> {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        fs::create_dir_all(dir)?;
+        let stem = format!("{}-{}", secs, process::id());
+        let mut name = stem.clone();
+        for tries in 1.. {
+            let path = dir.join(format!("{}.json", name));
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(Session::at(path)),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                    name = format!("{}-{}", stem, tries);
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        unreachable!()
+    }
+
+    /// An existing session, by name or by path to its file.
+    pub fn open(name: &str) -> Result<Session> {
+        let path = Path::new(name);
+        let session = if path.is_file() {
+            Session::at(path)
+        } else {
+            Session::at(sessions_dir()?.join(format!("{}.json", name)))
+        };
+        if !session.path.is_file() {
+            return Err(Error::BadArgument(format!("no session {}", name)));
+        }
+        Ok(session)
+    }
+
+    pub fn at<P: AsRef<Path>>(path: P) -> Session {
+        Session {
+            path: path.as_ref().to_owned(),
+        }
+    }
+
+    /// What to pass to `--resume`.
+    pub fn name(&self) -> String {
+        self.path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The files its `COPY` instructions copy.
+    pub fn context(&self) -> ManagedContext {
+        ManagedContext::at(self.path.with_extension("context"))
+    }
+
+    /// Writes the history out. Goes via a temporary file so a crash
+    /// part way through leaves the previous save intact.
+    pub fn save(&self, history: &History) -> Result<()> {
+        let file = SessionFile {
+            format: FORMAT,
+            history: history.clone(),
+        };
+        let json = serde_json::to_string_pretty(&file).map_err(|err| self.error(err))?;
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, json)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    pub fn load(&self) -> Result<History> {
+        let json = fs::read_to_string(&self.path)?;
+        let file: SessionFile = serde_json::from_str(&json).map_err(|err| self.error(err))?;
+        if file.format != FORMAT {
+            return Err(self.error(format!("unknown format {}", file.format)));
+        }
+        Ok(file.history)
+    }
+
+    fn error<E: ToString>(&self, err: E) -> Error {
+        Error::BadSession {
+            path: self.path.display().to_string(),
+            message: err.to_string(),
+        }
+    }
+}
+
+fn sessions_dir() -> Result<PathBuf> {
+    dirs::data_dir()
+        .map(|dir| dir.join("dockershell").join("sessions"))
+        .ok_or_else(|| Error::BadArgument("no data directory to keep sessions in".to_owned()))
+}
+
+/// `State::lines` is saved as Dockerfile text, which reads better than the enum.
+pub(crate) mod dockerfile_text {
+    use serde::de::Error as _;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::super::{parse_dockerfile, to_dockerfile, Instruction};
+
+    pub fn serialize<S: Serializer>(lines: &[Instruction], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&to_dockerfile(lines))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Instruction>, D::Error> {
+        let text = String::deserialize(d)?;
+        parse_dockerfile(&text).map_err(|err| D::Error::custom(err.to_string()))
+    }
+}
@@ -0,0 +1,205 @@
+//! Interactive commands: keystrokes go to the container as they are typed,
+//! output comes straight back, and the container's terminal keeps our size.
+
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use termion::raw::IntoRawMode;
+
+use super::error::Result;
+use super::interrupt::Watch;
+use super::runtime::ContainerRuntime;
+
+/// How long to wait for a keystroke before checking on everything else.
+const POLL_MS: libc::c_int = 50;
+
+/// What Ctrl-C types in raw mode.
+const CTRL_C: u8 = 3;
+
+/// Set when our terminal has changed size.
+static RESIZED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigwinch(_: libc::c_int) {
+    RESIZED.store(true, Ordering::SeqCst);
+}
+
+/// Puts the previous SIGWINCH handler back on drop.
+struct WatchResizes(libc::sighandler_t);
+
+impl WatchResizes {
+    fn new() -> WatchResizes {
+        let handler: extern "C" fn(libc::c_int) = on_sigwinch;
+        WatchResizes(unsafe { libc::signal(libc::SIGWINCH, handler as libc::sighandler_t) })
+    }
+}
+
+impl Drop for WatchResizes {
+    fn drop(&mut self) {
+        unsafe { libc::signal(libc::SIGWINCH, self.0) };
+    }
+}
+
+/// Connects our terminal to a tty exec until its output ends. Output from
+/// `marker` on is collected but not shown. Returns all of it, or Err as soon
+/// as `watch` says to give up (Ctrl-C is not passed on, but stops it).
+pub(crate) fn pump(
+    runtime: &dyn ContainerRuntime,
+    exec_id: &str,
+    mut output: Box<dyn Read + Send>,
+    mut input: Box<dyn Write + Send>,
+    marker: &str,
+    watch: &Watch,
+) -> Result<String> {
+    // Both restore the terminal when dropped, panicking or not.
+    let _raw = io::stdout().into_raw_mode()?;
+    let _resizes = WatchResizes::new();
+    resize(runtime, exec_id);
+
+    let done = Arc::new(AtomicBool::new(false));
+    let copier = {
+        let done = done.clone();
+        // The tty turns the trailer's leading newline into \r\n.
+        let hide_from = format!("\r\n{}", marker).into_bytes();
+        thread::spawn(move || {
+            let copied = copy_output(&mut output, &hide_from);
+            done.store(true, Ordering::SeqCst);
+            copied
+        })
+    };
+
+    let mut forwarding = true;
+    let mut buf = [0u8; 1024];
+    while !done.load(Ordering::SeqCst) {
+        // The output thread is left to end with the container.
+        watch.check()?;
+        if RESIZED.swap(false, Ordering::SeqCst) {
+            resize(runtime, exec_id);
+        }
+        if !forwarding {
+            thread::sleep(Duration::from_millis(POLL_MS as u64));
+            continue;
+        }
+        if !stdin_readable()? {
+            continue;
+        }
+        let read = unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr() as *mut _, buf.len()) };
+        if read <= 0 {
+            // Our stdin is closed: nothing more to send.
+            forwarding = false;
+            continue;
+        }
+        let typed = &buf[..read as usize];
+        if typed.contains(&CTRL_C) {
+            watch.interrupt();
+            continue;
+        }
+        // The exec may already have gone, in which case the output ends too.
+        forwarding = input.write_all(typed).and_then(|_| input.flush()).is_ok();
+    }
+
+    let collected = copier.join().expect("tty output thread panicked")?;
+    // Raw mode tty output ends lines with \r\n.
+    Ok(String::from_utf8_lossy(&collected).replace("\r\n", "\n"))
+}
+
+fn resize(runtime: &dyn ContainerRuntime, exec_id: &str) {
+    if let Ok((cols, rows)) = termion::terminal_size() {
+        // Fails if the exec has just finished, which is fine.
+        let _ = runtime.resize_exec(exec_id, rows, cols);
+    }
+}
+
+/// Whether a keystroke is waiting, giving it `POLL_MS` to arrive.
+fn stdin_readable() -> io::Result<bool> {
+    let mut fds = libc::pollfd {
+        fd: libc::STDIN_FILENO,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    match unsafe { libc::poll(&mut fds, 1, POLL_MS) } {
+        -1 => {
+            let err = io::Error::last_os_error();
+            // E.g. SIGWINCH.
+            if err.kind() == io::ErrorKind::Interrupted {
+                Ok(false)
+            } else {
+                Err(err)
+            }
+        }
+        0 => Ok(false),
+        _ => Ok(fds.revents & (libc::POLLIN | libc::POLLHUP) != 0),
+    }
+}
+
+/// Copies output to our stdout as it arrives, up to `hide_from`.
+/// Bytes that might be the start of it are held back until we know.
+fn copy_output(output: &mut dyn Read, hide_from: &[u8]) -> io::Result<Vec<u8>> {
+    let stdout = io::stdout();
+    let mut collected = vec![];
+    let mut shown = 0;
+    let mut hiding = false;
+    let mut buf = [0u8; 4096];
+    loop {
+        let read = match output.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        collected.extend_from_slice(&buf[..read]);
+        if hiding {
+            continue;
+        }
+        let pending = &collected[shown..];
+        let end = match find(pending, hide_from) {
+            Some(at) => {
+                hiding = true;
+                shown + at
+            }
+            None => collected.len() - partial_match(pending, hide_from),
+        };
+        let mut out = stdout.lock();
+        out.write_all(&collected[shown..end])?;
+        out.flush()?;
+        shown = end;
+    }
+    if !hiding {
+        // Ended without a trailer: what was held back was just output.
+        let mut out = stdout.lock();
+        out.write_all(&collected[shown..])?;
+        out.flush()?;
+    }
+    Ok(collected)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Length of the longest end of `haystack` that `needle` starts with.
+fn partial_match(haystack: &[u8], needle: &[u8]) -> usize {
+    (1..needle.len().min(haystack.len() + 1))
+        .rev()
+        .find(|&len| haystack.ends_with(&needle[..len]))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find, partial_match};
+
+    #[test]
+    fn holds_back_only_what_could_be_the_marker() {
+        let marker = b"\r\n--dockershell-1--";
+        assert_eq!(partial_match(b"ls\r\nbin\r\n", marker), 2);
+        assert_eq!(partial_match(b"ls\r\nbin\r\n--docker", marker), 10);
+        assert_eq!(partial_match(b"ls\r\nbin", marker), 0);
+        assert_eq!(find(b"bin\r\n--dockershell-1--\r\nPWD=/", marker), Some(3));
+        assert_eq!(find(b"bin\r\n", marker), None);
+    }
+}
@@ -1,5 +1,15 @@
 mod tests {
-    use dockershell::{interpreter_loop_from_file, ExecListener, LineResult, State};
+    use dockershell::dockerfile::{self, Arguments, Span};
+    use dockershell::{
+        interpreter_loop, interpreter_loop_from_file, parse_dockerfile, parse_line, resume_loop,
+        to_dockerfile, Change, ChangeKind, DockerRuntime, Error, ExecListener, FakeCommand,
+        FakeRuntime, FileChange, History, IgnoreRules, Instruction, LineResult, ReadPrompt,
+        Recording, ScratchContainers, Session, State, MAIN_BRANCH,
+    };
+    use futures::executor::block_on;
+    use rustyline::error::ReadlineError;
+    use std::collections::BTreeMap;
+    use std::time::Duration;
 
     struct Checker<'l> {
         next: usize,
@@ -11,10 +21,12 @@ mod tests {
             &mut self,
             _line: &str,
             _state: &State,
-            line_result: Result<&LineResult, ()>,
+            line_result: Result<&LineResult, &Error>,
         ) {
             println!("checking step index {}", self.next);
-            let mut expected = self.expected_results[self.next].clone();
+            let mut expected = self.expected_results[self.next];
+            // Only whether it failed is checked, not why.
+            let line_result = line_result.map_err(|_| ());
             let line_result_ref: LineResult;
 
             // Ignore image_name in comparison as it is random generated.
@@ -31,31 +43,64 @@ mod tests {
         }
     }
 
+    /// `State::layer_changes` of layers that made these changes, with no sizes.
+    fn layer_changes(
+        layers: &[(usize, &[(&str, ChangeKind)])],
+    ) -> BTreeMap<usize, Vec<FileChange>> {
+        layers
+            .iter()
+            .map(|(layer, changes)| {
+                let changes = changes
+                    .iter()
+                    .map(|(path, kind)| FileChange::new(path, *kind, None))
+                    .collect();
+                (*layer, changes)
+            })
+            .collect()
+    }
+
     #[test]
     fn state_change_cd_up() {
         let state = State {
             lines: vec![
-                vec!["FROM".to_owned(), "alpine:edge".to_owned()],
-                vec!["cd ..".to_owned()],
+                Instruction::from_image("alpine:edge"),
+                Instruction::run("cd /bin"),
+                Instruction::run("cd .."),
             ],
             ..State::test()
         };
 
         interpreter_loop_from_file(
             state.clone(),
+            &DockerRuntime::connect_with_defaults().unwrap(),
             &mut Checker {
                 next: 0,
-                expected_results: vec![Ok(&LineResult::State(
-                    State {
-                        lines: vec![
-                            vec!["FROM".to_owned(), "alpine:edge".to_owned()],
-                            vec!["WORKDIR".to_owned(), "/".to_owned()],
-                        ],
-                        pwd: "/".to_owned(),
-                        ..state
-                    },
-                    "/\n".to_owned(),
-                ))],
+                expected_results: vec![
+                    Ok(&LineResult::State(
+                        State {
+                            lines: vec![
+                                Instruction::from_image("alpine:edge"),
+                                Instruction::workdir("/bin"),
+                            ],
+                            pwd: "/bin".to_owned(),
+                            oldpwd: "/".to_owned(),
+                            ..state.clone()
+                        },
+                        "".to_owned(),
+                    )),
+                    Ok(&LineResult::State(
+                        State {
+                            lines: vec![
+                                Instruction::from_image("alpine:edge"),
+                                Instruction::workdir("/"),
+                            ],
+                            pwd: "/".to_owned(),
+                            oldpwd: "/bin".to_owned(),
+                            ..state
+                        },
+                        "".to_owned(),
+                    )),
+                ],
             },
         )
         .unwrap();
@@ -65,37 +110,29 @@ mod tests {
     fn state_change_cd_root() {
         let state = State {
             lines: vec![
-                vec!["FROM".to_owned(), "alpine:edge".to_owned()],
-                vec!["cd /".to_owned()],
-                vec!["mkdir temp".to_owned()],
+                Instruction::from_image("alpine:edge"),
+                Instruction::run("cd /"),
+                Instruction::run("mkdir temp"),
             ],
             ..State::test()
         };
 
         interpreter_loop_from_file(
             state.clone(),
+            &DockerRuntime::connect_with_defaults().unwrap(),
             &mut Checker {
                 next: 0,
                 expected_results: vec![
+                    // Already there.
+                    Ok(&LineResult::NoOp("".to_owned())),
                     Ok(&LineResult::State(
                         State {
                             lines: vec![
-                                vec!["FROM".to_owned(), "alpine:edge".to_owned()],
-                                vec!["WORKDIR".to_owned(), "/".to_owned()],
-                            ],
-                            pwd: "/".to_owned(),
-                            ..state.clone()
-                        },
-                        "/\n".to_owned(),
-                    )),
-                    Ok(&LineResult::State(
-                        State {
-                            lines: vec![
-                                vec!["FROM".to_owned(), "alpine:edge".to_owned()],
-                                vec!["WORKDIR".to_owned(), "/".to_owned()],
-                                vec!["RUN".to_owned(), "mkdir temp".to_owned()],
+                                Instruction::from_image("alpine:edge"),
+                                Instruction::run("mkdir temp"),
                             ],
                             pwd: "/".to_owned(),
+                            layer_changes: layer_changes(&[(1, &[("/temp", ChangeKind::Added)])]),
                             ..state
                         },
                         "".to_owned(),
@@ -110,22 +147,30 @@ mod tests {
     fn state_change_mk_dir() {
         let state = State {
             lines: vec![
-                vec!["FROM".to_owned(), "alpine:edge".to_owned()],
-                vec!["mkdir /bin/foo".to_owned()],
+                Instruction::from_image("alpine:edge"),
+                Instruction::run("mkdir /bin/foo"),
             ],
             ..State::test()
         };
 
         interpreter_loop_from_file(
             state.clone(),
+            &DockerRuntime::connect_with_defaults().unwrap(),
             &mut Checker {
                 next: 0,
                 expected_results: vec![Ok(&LineResult::State(
                     State {
                         lines: vec![
-                            vec!["FROM".to_owned(), "alpine:edge".to_owned()],
-                            vec!["RUN".to_owned(), "mkdir /bin/foo".to_owned()],
+                            Instruction::from_image("alpine:edge"),
+                            Instruction::run("mkdir /bin/foo"),
                         ],
+                        layer_changes: layer_changes(&[(
+                            1,
+                            &[
+                                ("/bin", ChangeKind::Modified),
+                                ("/bin/foo", ChangeKind::Added),
+                            ],
+                        )]),
                         ..state
                     },
                     "".to_owned(),
@@ -139,24 +184,32 @@ mod tests {
     fn mk_and_rm_dir() {
         let state = State {
             lines: vec![
-                vec!["FROM".to_owned(), "alpine:edge".to_owned()],
-                vec!["mkdir /bin/foo".to_owned()],
-                vec!["rmdir /bin/foo".to_owned()],
+                Instruction::from_image("alpine:edge"),
+                Instruction::run("mkdir /bin/foo"),
+                Instruction::run("rmdir /bin/foo"),
             ],
             ..State::test()
         };
 
         interpreter_loop_from_file(
             state.clone(),
+            &DockerRuntime::connect_with_defaults().unwrap(),
             &mut Checker {
                 next: 0,
                 expected_results: vec![
                     Ok(&LineResult::State(
                         State {
                             lines: vec![
-                                vec!["FROM".to_owned(), "alpine:edge".to_owned()],
-                                vec!["RUN".to_owned(), "mkdir /bin/foo".to_owned()],
+                                Instruction::from_image("alpine:edge"),
+                                Instruction::run("mkdir /bin/foo"),
                             ],
+                            layer_changes: layer_changes(&[(
+                                1,
+                                &[
+                                    ("/bin", ChangeKind::Modified),
+                                    ("/bin/foo", ChangeKind::Added),
+                                ],
+                            )]),
                             ..state.clone()
                         },
                         "".to_owned(),
@@ -164,10 +217,26 @@ mod tests {
                     Ok(&LineResult::State(
                         State {
                             lines: vec![
-                                vec!["FROM".to_owned(), "alpine:edge".to_owned()],
-                                vec!["RUN".to_owned(), "mkdir /bin/foo".to_owned()],
-                                vec!["RUN".to_owned(), "rmdir /bin/foo".to_owned()],
+                                Instruction::from_image("alpine:edge"),
+                                Instruction::run("mkdir /bin/foo"),
+                                Instruction::run("rmdir /bin/foo"),
                             ],
+                            layer_changes: layer_changes(&[
+                                (
+                                    1,
+                                    &[
+                                        ("/bin", ChangeKind::Modified),
+                                        ("/bin/foo", ChangeKind::Added),
+                                    ],
+                                ),
+                                (
+                                    2,
+                                    &[
+                                        ("/bin", ChangeKind::Modified),
+                                        ("/bin/foo", ChangeKind::Deleted),
+                                    ],
+                                ),
+                            ]),
                             ..state
                         },
                         "".to_owned(),
@@ -182,14 +251,15 @@ mod tests {
     fn no_op_with_output_to_std_err() {
         let state = State {
             lines: vec![
-                vec!["FROM".to_owned(), "alpine:edge".to_owned()],
-                vec![">&2 echo Hello".to_owned()],
+                Instruction::from_image("alpine:edge"),
+                Instruction::run(">&2 echo Hello"),
             ],
             ..State::test()
         };
 
         interpreter_loop_from_file(
             state.clone(),
+            &DockerRuntime::connect_with_defaults().unwrap(),
             &mut Checker {
                 next: 0,
                 expected_results: vec![Ok(&LineResult::NoOp("Hello\n".to_owned()))],
@@ -200,59 +270,1581 @@ mod tests {
 
     #[test]
     fn change_to_bad_dir_should_not_panic() {
+        let runtime = DockerRuntime::connect_with_defaults().unwrap();
+        let scratch = ScratchContainers::new();
+        let state = State {
+            image_name: "alpine:edge".to_owned(),
+            pwd: "/".to_owned(),
+            ..State::test()
+        };
+
+        match parse_line("cd ....", &state, &runtime, &scratch) {
+            Ok((LineResult::Failed(_, output), None)) => {
+                assert!(output.contains("can't cd to ...."), "{}", output)
+            }
+            other => panic!("unexpected {:?}", other.map(|(result, _)| result)),
+        }
+        match parse_line("echo Hi", &state, &runtime, &scratch) {
+            Ok((LineResult::NoOp(output), None)) => assert_eq!(output, "Hi\n"),
+            other => panic!("unexpected {:?}", other.map(|(result, _)| result)),
+        }
+        scratch.remove_all(&runtime).unwrap();
+    }
+
+    #[test]
+    fn change_dir_should_go_up_a_dir() {
+        let state = State {
+            lines: vec![
+                Instruction::from_image("alpine:edge"),
+                Instruction::run("cd /usr/bin"),
+                Instruction::run("cd .."),
+                Instruction::run("pwd"),
+            ],
+            ..State::test()
+        };
+
+        interpreter_loop_from_file(
+            state.clone(),
+            &DockerRuntime::connect_with_defaults().unwrap(),
+            &mut Checker {
+                next: 0,
+                expected_results: vec![
+                    Ok(&LineResult::State(
+                        State {
+                            lines: vec![
+                                Instruction::from_image("alpine:edge"),
+                                Instruction::workdir("/usr/bin"),
+                            ],
+                            pwd: "/usr/bin".to_owned(),
+                            oldpwd: "/".to_owned(),
+                            ..state.clone()
+                        },
+                        "".to_owned(),
+                    )),
+                    Ok(&LineResult::State(
+                        State {
+                            lines: vec![
+                                Instruction::from_image("alpine:edge"),
+                                Instruction::workdir("/usr"),
+                            ],
+                            pwd: "/usr".to_owned(),
+                            oldpwd: "/usr/bin".to_owned(),
+                            ..state
+                        },
+                        "".to_owned(),
+                    )),
+                    Ok(&LineResult::NoOp("/usr\n".to_owned())),
+                ],
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn cd_back_and_pushd_popd() {
         let state = State {
             lines: vec![
-                vec!["FROM".to_owned(), "alpine:edge".to_owned()],
-                vec!["cd ....".to_owned()],
-                vec!["echo Hi".to_owned()],
+                Instruction::from_image("alpine:edge"),
+                Instruction::run("cd /usr"),
+                Instruction::run("pushd /etc"),
+                Instruction::run("cd -"),
+                Instruction::run("popd"),
             ],
             ..State::test()
         };
+        let at = |dir: &str, oldpwd: &str, stack: &[&str]| State {
+            lines: vec![
+                Instruction::from_image("alpine:edge"),
+                Instruction::workdir(dir),
+            ],
+            pwd: dir.to_owned(),
+            oldpwd: oldpwd.to_owned(),
+            dir_stack: stack.iter().map(|dir| dir.to_string()).collect(),
+            ..state.clone()
+        };
 
         interpreter_loop_from_file(
             state.clone(),
+            &DockerRuntime::connect_with_defaults().unwrap(),
             &mut Checker {
                 next: 0,
-                expected_results: vec![Err(()), Ok(&LineResult::NoOp("Hi\n".to_owned()))],
+                expected_results: vec![
+                    Ok(&LineResult::State(at("/usr", "/", &[]), "".to_owned())),
+                    Ok(&LineResult::State(
+                        at("/etc", "/usr", &["/usr"]),
+                        "".to_owned(),
+                    )),
+                    Ok(&LineResult::State(
+                        at("/usr", "/etc", &["/usr"]),
+                        "/usr\n".to_owned(),
+                    )),
+                    Ok(&LineResult::State(at("/usr", "/usr", &[]), "".to_owned())),
+                ],
             },
         )
         .unwrap();
     }
 
     #[test]
-    fn change_dir_should_go_up_a_dir() {
+    fn fake_runtime_records_state_change_offline() {
+        let runtime = FakeRuntime::new().on(
+            "mkdir /bin/foo",
+            FakeCommand::default().changes(vec![Change {
+                path: "/bin/foo".to_owned(),
+                kind: ChangeKind::Added,
+            }]),
+        );
         let state = State {
             lines: vec![
-                vec!["FROM".to_owned(), "alpine:edge".to_owned()],
-                vec!["cd ..".to_owned()],
-                vec!["pwd".to_owned()],
-                vec!["pwd".to_owned()],
+                Instruction::from_image("alpine:edge"),
+                Instruction::run("mkdir /bin/foo"),
+                Instruction::run("ls /bin/foo"),
             ],
+            pwd: "/".to_owned(),
             ..State::test()
         };
 
         interpreter_loop_from_file(
             state.clone(),
+            &runtime,
             &mut Checker {
                 next: 0,
                 expected_results: vec![
                     Ok(&LineResult::State(
                         State {
                             lines: vec![
-                                vec!["FROM".to_owned(), "alpine:edge".to_owned()],
-                                vec!["WORKDIR".to_owned(), "/".to_owned()],
+                                Instruction::from_image("alpine:edge"),
+                                Instruction::run("mkdir /bin/foo"),
+                            ],
+                            layer_changes: layer_changes(&[(
+                                1,
+                                &[("/bin/foo", ChangeKind::Added)],
+                            )]),
+                            ..state
+                        },
+                        "".to_owned(),
+                    )),
+                    Ok(&LineResult::NoOp("".to_owned())),
+                ],
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            runtime.commands(),
+            vec!["pwd", "mkdir /bin/foo", "ls /bin/foo"]
+        );
+        assert_eq!(runtime.containers(), 0);
+        // The layer is the container the command ran in, not a rebuild.
+        assert!(runtime.builds().is_empty());
+        let commits = runtime.commits();
+        assert_eq!(commits.len(), 1);
+        assert_eq!(commits[0].comment, "RUN mkdir /bin/foo");
+        assert!(commits[0].changes.is_empty());
+    }
+
+    #[test]
+    fn fake_runtime_cd_sets_workdir() {
+        let runtime = FakeRuntime::new()
+            .on("cd /bin", FakeCommand::default().cd("/bin"))
+            .on("cd ..", FakeCommand::default().cd("/"))
+            .on(
+                "mkdir -p /src && cd /src",
+                FakeCommand::default()
+                    .changes(vec![Change {
+                        path: "/src".to_owned(),
+                        kind: ChangeKind::Added,
+                    }])
+                    .cd("/src"),
+            );
+        let state = State {
+            lines: vec![
+                Instruction::from_image("alpine:edge"),
+                Instruction::run("cd /bin"),
+                Instruction::run("cd .."),
+                Instruction::run("mkdir -p /src && cd /src"),
+            ],
+            pwd: "/".to_owned(),
+            ..State::test()
+        };
+
+        interpreter_loop_from_file(
+            state.clone(),
+            &runtime,
+            &mut Checker {
+                next: 0,
+                expected_results: vec![
+                    Ok(&LineResult::State(
+                        State {
+                            lines: vec![
+                                Instruction::from_image("alpine:edge"),
+                                Instruction::workdir("/bin"),
+                            ],
+                            pwd: "/bin".to_owned(),
+                            oldpwd: "/".to_owned(),
+                            ..state.clone()
+                        },
+                        "".to_owned(),
+                    )),
+                    Ok(&LineResult::State(
+                        State {
+                            lines: vec![
+                                Instruction::from_image("alpine:edge"),
+                                Instruction::workdir("/"),
                             ],
                             pwd: "/".to_owned(),
+                            oldpwd: "/bin".to_owned(),
                             ..state.clone()
                         },
-                        "/\n".to_owned(),
+                        "".to_owned(),
+                    )),
+                    Ok(&LineResult::State(
+                        State {
+                            lines: vec![
+                                Instruction::from_image("alpine:edge"),
+                                Instruction::workdir("/"),
+                                Instruction::run("mkdir -p /src && cd /src"),
+                                Instruction::workdir("/src"),
+                            ],
+                            pwd: "/src".to_owned(),
+                            oldpwd: "/".to_owned(),
+                            layer_changes: layer_changes(&[(2, &[("/src", ChangeKind::Added)])]),
+                            ..state
+                        },
+                        "".to_owned(),
                     )),
-                    Ok(&LineResult::NoOp("/\n".to_owned())),
-                    Ok(&LineResult::NoOp("/\n".to_owned())),
                 ],
             },
         )
         .unwrap();
+
+        let commits = runtime.commits();
+        assert_eq!(commits.len(), 3);
+        assert_eq!(
+            commits[2].comment,
+            "RUN mkdir -p /src && cd /src; WORKDIR /src"
+        );
+        assert_eq!(commits[2].changes, vec!["WORKDIR /src"]);
+    }
+
+    #[test]
+    fn read_only_commands_share_a_container() {
+        let runtime = FakeRuntime::new().on("pwd", FakeCommand::output("/\n")).on(
+            "mkdir a",
+            FakeCommand::default().changes(vec![Change {
+                path: "/a".to_owned(),
+                kind: ChangeKind::Added,
+            }]),
+        );
+        let mut state = State {
+            lines: vec![Instruction::from_image("alpine:edge")],
+            ..State::test()
+        };
+        for line in &["ls", "cat /etc/os-release", "mkdir a", "ls", "ls /a"] {
+            state.lines.push(Instruction::run(line));
+        }
+
+        interpreter_loop_from_file(state, &runtime, &mut Ignore).unwrap();
+
+        // One for the base image, one for the layer `mkdir a` made.
+        assert_eq!(runtime.containers_created(), 2);
+        assert_eq!(runtime.containers(), 0);
+        let commits = runtime.commits();
+        assert_eq!(commits.len(), 1);
+        let images: Vec<String> = runtime.runs().into_iter().map(|(image, _)| image).collect();
+        assert_eq!(
+            images,
+            vec![
+                "alpine:edge",
+                "alpine:edge",
+                "alpine:edge",
+                "alpine:edge",
+                &commits[0].tag,
+                &commits[0].tag
+            ]
+        );
+    }
+
+    #[test]
+    fn exports_become_env_instructions() {
+        let runtime = FakeRuntime::new()
+            .on(
+                "export PATH=/opt/bin:$PATH GREETING='hi $there'",
+                FakeCommand::default()
+                    .export("PATH", "/opt/bin:/usr/bin")
+                    .export("GREETING", "hi $there"),
+            )
+            .on("unset GREETING", FakeCommand::default().unset("GREETING"));
+        let scratch = ScratchContainers::new();
+        let state = State {
+            lines: vec![Instruction::from_image("alpine:edge")],
+            pwd: "/".to_owned(),
+            ..State::test()
+        };
+
+        let run = |line: &str, state: &State| match parse_line(line, state, &runtime, &scratch) {
+            Ok((LineResult::State(state, _), None)) => state,
+            Ok((other, _)) => panic!("{}: {:?}", line, other),
+            Err(err) => panic!("{}: {}", line, err),
+        };
+        let exported = run("export PATH=/opt/bin:$PATH GREETING='hi $there'", &state);
+        assert_eq!(
+            exported.last_instruction(),
+            &Instruction::Env(vec![
+                ("GREETING".to_owned(), "hi \\$there".to_owned()),
+                ("PATH".to_owned(), "/opt/bin:/usr/bin".to_owned()),
+            ])
+        );
+        let unset = run("unset GREETING", &exported);
+        assert_eq!(
+            unset.last_instruction(),
+            &Instruction::Env(vec![("GREETING".to_owned(), String::new())])
+        );
+        match parse_line("ls", &unset, &runtime, &scratch) {
+            Ok((LineResult::NoOp(_), None)) => {}
+            other => panic!("{:?}", other.map(|(result, _)| result)),
+        }
+        scratch.remove_all(&runtime).unwrap();
+
+        // Each environment gets a container of its own, started with the exports.
+        assert_eq!(runtime.containers_created(), 3);
+        assert_eq!(
+            runtime.commands(),
+            vec![
+                "export PATH=/opt/bin:$PATH GREETING='hi $there'",
+                "unset GREETING",
+                "ls"
+            ]
+        );
+        assert_eq!(
+            Instruction::Env(vec![("HOME_PATH".to_owned(), "\\$HOME/bin x".to_owned())]).to_shell(),
+            Some("export HOME_PATH=\"\\$HOME/bin x\"".to_owned())
+        );
+    }
+
+    #[test]
+    fn failed_commands_are_not_recorded_unless_forced() {
+        let added = FakeCommand::default()
+            .changes(vec![Change {
+                path: "/lib/apk/db/lock".to_owned(),
+                kind: ChangeKind::Added,
+            }])
+            .exit(1);
+        let runtime = FakeRuntime::new()
+            .on("apk add nonexistent", added.clone())
+            .on("false", FakeCommand::default().exit(1))
+            .on("make", added.clone().exit(2).exits_after(3));
+        let scratch = ScratchContainers::new();
+        let state = State {
+            lines: vec![Instruction::from_image("alpine:edge")],
+            pwd: "/".to_owned(),
+            ..State::test()
+        };
+
+        match parse_line("apk add nonexistent", &state, &runtime, &scratch) {
+            Ok((LineResult::Failed(1, _), None)) => {}
+            other => panic!("{:?}", other.map(|(result, _)| result)),
+        }
+        assert!(runtime.commits().is_empty());
+        match parse_line("false", &state, &runtime, &scratch) {
+            Ok((LineResult::Failed(1, _), None)) => {}
+            other => panic!("{:?}", other.map(|(result, _)| result)),
+        }
+        // Still running for a moment after its output ended.
+        match parse_line("make", &state, &runtime, &scratch) {
+            Ok((LineResult::Failed(2, _), None)) => {}
+            other => panic!("{:?}", other.map(|(result, _)| result)),
+        }
+        assert!(runtime.commits().is_empty());
+
+        let committing = match parse_line("!!apk add nonexistent", &state, &runtime, &scratch) {
+            Ok((LineResult::State(state, _), Some(committing))) => {
+                assert_eq!(
+                    state.last_instruction(),
+                    &Instruction::run("apk add nonexistent")
+                );
+                committing
+            }
+            other => panic!("{:?}", other.map(|(result, _)| result)),
+        };
+        // The commit was started at once, and removes its container when done.
+        block_on(committing).unwrap();
+        assert_eq!(runtime.commits().len(), 1);
+        scratch.remove_all(&runtime).unwrap();
+        assert_eq!(runtime.containers(), 0);
+    }
+
+    #[test]
+    fn prefixes_decide_what_is_recorded() {
+        let added = FakeCommand::default().changes(vec![Change {
+            path: "/a".to_owned(),
+            kind: ChangeKind::Added,
+        }]);
+        let runtime = FakeRuntime::new()
+            .on("mkdir a", added)
+            .on("true", FakeCommand::default());
+        let scratch = ScratchContainers::new();
+        let state = State {
+            lines: vec![Instruction::from_image("alpine:edge")],
+            pwd: "/".to_owned(),
+            ..State::test()
+        };
+
+        match parse_line("?mkdir a", &state, &runtime, &scratch) {
+            Ok((LineResult::Tried(_), None)) => {}
+            other => panic!("{:?}", other.map(|(result, _)| result)),
+        }
+        // Thrown away with what it changed.
+        assert_eq!(runtime.containers(), 0);
+        match parse_line("!! true", &state, &runtime, &scratch) {
+            Ok((LineResult::State(state, _), Some(_))) => {
+                assert_eq!(state.last_instruction(), &Instruction::run("true"));
+                assert_eq!(state.layer_changes, layer_changes(&[(1, &[])]));
+            }
+            other => panic!("{:?}", other.map(|(result, _)| result)),
+        }
+        scratch.remove_all(&runtime).unwrap();
+        assert_eq!(runtime.containers(), 0);
+
+        let (recording, command) = Recording::of("  ? ls");
+        assert_eq!((recording, command), (Recording::Never, "ls"));
+    }
+
+    struct Overrides(Vec<(String, Recording)>);
+
+    impl ExecListener for Overrides {
+        fn command_run(&mut self, _: &str, _: &State, _: Result<&LineResult, &Error>) {}
+
+        fn recording_overridden(&mut self, line: &str, recording: Recording) {
+            self.0.push((line.to_owned(), recording));
+        }
+    }
+
+    #[test]
+    fn listeners_hear_of_overridden_recording() {
+        let runtime = FakeRuntime::new();
+        let mut state = State {
+            lines: vec![Instruction::from_image("alpine:edge")],
+            ..State::test()
+        };
+        for line in &["ls", "?ls", "!!ls"] {
+            state.lines.push(Instruction::run(line));
+        }
+
+        let mut overrides = Overrides(vec![]);
+        interpreter_loop_from_file(state, &runtime, &mut overrides).unwrap();
+        assert_eq!(
+            overrides.0,
+            vec![
+                ("?ls".to_owned(), Recording::Never),
+                ("!!ls".to_owned(), Recording::Always)
+            ]
+        );
+    }
+
+    struct Timeouts(Vec<(String, Duration)>);
+
+    impl ExecListener for Timeouts {
+        fn command_run(&mut self, _: &str, _: &State, _: Result<&LineResult, &Error>) {}
+
+        fn command_timed_out(&mut self, line: &str, after: Duration) {
+            self.0.push((line.to_owned(), after));
+        }
+    }
+
+    #[test]
+    fn commands_that_run_too_long_are_stopped() {
+        let runtime = FakeRuntime::new()
+            .on(
+                "mkdir a; sleep 5",
+                FakeCommand::adds("/a").runs_for(Duration::from_secs(5)),
+            )
+            .on("mkdir b", FakeCommand::adds("/b"));
+        let timeout = Duration::from_millis(100);
+        let mut state = State {
+            lines: vec![Instruction::from_image("alpine:edge")],
+            timeout: Some(timeout),
+            ..State::test()
+        };
+        for line in &["mkdir a; sleep 5", "mkdir b"] {
+            state.lines.push(Instruction::run(line));
+        }
+
+        let mut timeouts = Timeouts(vec![]);
+        interpreter_loop_from_file(state, &runtime, &mut timeouts).unwrap();
+        assert_eq!(timeouts.0, vec![("mkdir a; sleep 5".to_owned(), timeout)]);
+        // The session carried on without it.
+        let commits = runtime.commits();
+        assert_eq!(commits.len(), 1);
+        assert!(commits[0].comment.contains("mkdir b"));
+        assert_eq!(runtime.containers(), 0);
+    }
+
+    struct Ignore;
+
+    impl ExecListener for Ignore {
+        fn command_run(&mut self, _: &str, _: &State, _: Result<&LineResult, &Error>) {}
+    }
+
+    #[test]
+    fn undo_and_redo_restore_images() {
+        let runtime = FakeRuntime::new()
+            .on("pwd", FakeCommand::output("/\n"))
+            .on("mkdir a", FakeCommand::adds("/a"))
+            .on("mkdir b", FakeCommand::adds("/b"));
+        let lines = vec![
+            "mkdir a", "mkdir b", "undo", "ls", "redo", "ls", "undo 1", "ls", "undo 5",
+        ];
+        let mut state = State {
+            lines: vec![Instruction::from_image("alpine:edge")],
+            ..State::test()
+        };
+        state.lines.extend(lines.into_iter().map(Instruction::run));
+
+        interpreter_loop_from_file(state, &runtime, &mut Ignore).unwrap();
+
+        let commits = runtime.commits();
+        assert_eq!(commits.len(), 2);
+        let builds = runtime.builds();
+        assert_eq!(builds.len(), 1);
+        assert_eq!(builds[0].1, "FROM alpine:edge\nRUN mkdir b");
+
+        let ls_images: Vec<String> = runtime
+            .runs()
+            .into_iter()
+            .filter(|(_, command)| command == "ls")
+            .map(|(image, _)| image)
+            .collect();
+        assert_eq!(
+            ls_images,
+            vec![
+                commits[0].tag.clone(),
+                commits[1].tag.clone(),
+                builds[0].0.clone()
+            ]
+        );
+    }
+
+    #[test]
+    fn undone_layers_are_rebuilt_one_on_another() {
+        let runtime = FakeRuntime::new()
+            .on("mkdir /a && cd /a", FakeCommand::adds("/a").cd("/a"))
+            .on("mkdir b", FakeCommand::adds("/a/b"))
+            .on("mkdir c", FakeCommand::adds("/a/c"));
+        let mut state = State {
+            lines: vec![Instruction::from_image("alpine:edge")],
+            ..State::test()
+        };
+        // Layer 2 is the `WORKDIR` the first command also records.
+        for line in &["mkdir /a && cd /a", "mkdir b", "mkdir c", "undo 2", "ls"] {
+            state.lines.push(Instruction::run(line));
+        }
+
+        interpreter_loop_from_file(state, &runtime, &mut Ignore).unwrap();
+
+        let builds = runtime.builds();
+        assert_eq!(builds.len(), 3);
+        assert_eq!(builds[0].1, "FROM alpine:edge\nRUN mkdir /a && cd /a");
+        assert_eq!(builds[1].1, format!("FROM {}\nRUN mkdir b", builds[0].0));
+        assert_eq!(builds[2].1, format!("FROM {}\nRUN mkdir c", builds[1].0));
+        assert_eq!(runtime.runs().last().unwrap().0, builds[2].0);
     }
 
+    #[test]
+    fn undone_directories_and_changes_are_forgotten() {
+        let runtime = FakeRuntime::new()
+            .on("mkdir /a && cd /a", FakeCommand::adds("/a").cd("/a"))
+            .on("mkdir b", FakeCommand::adds("/a/b"));
+        let path =
+            std::env::temp_dir().join(format!("dockershell-undo-{}.json", std::process::id()));
+        let session = Session::at(&path);
+        let mut rl = Lines(vec!["mkdir /a && cd /a", "mkdir b", "undo 2"]);
+        interpreter_loop(
+            State::test(),
+            &runtime,
+            &mut rl,
+            &mut Ignore,
+            Some(&session),
+        )
+        .unwrap();
+
+        let history = session.load().unwrap();
+        let state = history.current();
+        assert_eq!(
+            state.dockerfile(),
+            "FROM alpine:edge\nRUN mkdir /a && cd /a\nRUN mkdir b"
+        );
+        assert_eq!(state.pwd, "/");
+        assert!(state.layer_changes.is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn builds_use_a_private_context() {
+        let runtime = FakeRuntime::new()
+            .on("mkdir a", FakeCommand::adds("/a"))
+            .on("mkdir b", FakeCommand::adds("/b"));
+        let mut state = State {
+            lines: vec![Instruction::from_image("alpine:edge")],
+            ..State::test()
+        };
+        for line in &["mkdir a", "mkdir b", "undo 1"] {
+            state.lines.push(Instruction::run(line));
+        }
+
+        interpreter_loop_from_file(state, &runtime, &mut Ignore).unwrap();
+
+        let contexts = runtime.build_contexts();
+        assert_eq!(contexts.len(), 1);
+        assert!(contexts[0].starts_with(std::env::temp_dir()));
+        assert!(!contexts[0].exists());
+        assert!(!contexts[0].parent().unwrap().exists());
+    }
+
+    #[test]
+    fn undo_needs_a_layer_number() {
+        let runtime = FakeRuntime::new();
+        let state = State::test();
+        for line in &["undo x", "undo 0", "undo 1"] {
+            match parse_line(line, &state, &runtime, &ScratchContainers::new()).map(|(r, _)| r) {
+                Err(Error::BadArgument(_)) => {}
+                other => panic!("{}: {:?}", line, other),
+            }
+        }
+        let result =
+            parse_line("redo", &state, &runtime, &ScratchContainers::new()).map(|(r, _)| r);
+        assert_eq!(result.unwrap(), LineResult::Redo);
+    }
+
+    #[test]
+    fn checkpoints_branch_the_session() {
+        let runtime = FakeRuntime::new()
+            .on("pwd", FakeCommand::output("/\n"))
+            .on("mkdir a", FakeCommand::adds("/a"))
+            .on("mkdir b", FakeCommand::adds("/b"))
+            .on("mkdir c", FakeCommand::adds("/c"));
+        let lines = vec![
+            "mkdir a",
+            "checkpoint source",
+            "mkdir b",
+            "checkout main",
+            "ls",
+            "mkdir c",
+            "checkout source",
+            "ls",
+            "checkout nowhere",
+            "checkpoint main",
+        ];
+        let mut state = State {
+            lines: vec![Instruction::from_image("alpine:edge")],
+            ..State::test()
+        };
+        state.lines.extend(lines.into_iter().map(Instruction::run));
+
+        interpreter_loop_from_file(state, &runtime, &mut Ignore).unwrap();
+
+        let commits = runtime.commits();
+        let comments: Vec<&str> = commits.iter().map(|c| c.comment.as_str()).collect();
+        assert_eq!(comments, vec!["RUN mkdir a", "RUN mkdir b", "RUN mkdir c"]);
+
+        let ls_images: Vec<String> = runtime
+            .runs()
+            .into_iter()
+            .filter(|(_, command)| command == "ls")
+            .map(|(image, _)| image)
+            .collect();
+        assert_eq!(
+            ls_images,
+            vec![commits[0].tag.clone(), commits[1].tag.clone()]
+        );
+    }
+
+    #[test]
+    fn history_keeps_branches_apart() {
+        let base = State {
+            lines: vec![Instruction::from_image("alpine:edge")],
+            ..State::test()
+        };
+        let with = |line: &str| {
+            let mut state = base.clone();
+            state.lines.push(Instruction::run(line));
+            state
+        };
+        let mut history = History::new(base.clone());
+        history.checkpoint("packages").unwrap();
+        history.push(with("apk add make"));
+        history.checkout(MAIN_BRANCH).unwrap();
+        assert_eq!(history.current(), &base);
+        history.checkpoint("source").unwrap();
+        history.push(with("make install"));
+        history.current_mut().debug = false;
+
+        assert!(history.checkpoint("packages").is_err());
+        assert!(history.checkpoint("two words").is_err());
+        assert!(history.checkout("nowhere").is_err());
+
+        history.checkout("packages").unwrap();
+        assert_eq!(history.branch(), "packages");
+        assert_eq!(history.current().lines, with("apk add make").lines);
+        assert!(!history.current().debug);
+        history.undo().unwrap();
+        assert_eq!(history.current().lines, base.lines);
+
+        let names: Vec<&str> = history.branches().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["main", "packages", "source"]);
+        assert_eq!(
+            history.tip("source").unwrap().lines,
+            with("make install").lines
+        );
+    }
+
+    struct Lines<'a>(Vec<&'a str>);
+
+    impl<'a> ReadPrompt for Lines<'a> {
+        fn read_line(&mut self, _prompt: &str) -> Result<String, ReadlineError> {
+            if self.0.is_empty() {
+                return Err(ReadlineError::Eof);
+            }
+            Ok(self.0.remove(0).to_owned())
+        }
+
+        fn add_history_entry(&mut self, _val: &str) {}
+    }
+
+    #[test]
+    fn sessions_are_saved_and_resumed() {
+        let runtime = || {
+            FakeRuntime::new()
+                .on("cd /a", FakeCommand::default().cd("/a"))
+                .on(
+                    "mkdir a",
+                    FakeCommand::default().changes(vec![Change {
+                        path: "/a".to_owned(),
+                        kind: ChangeKind::Added,
+                    }]),
+                )
+        };
+        let path = std::env::temp_dir().join(format!("dockershell-{}.json", std::process::id()));
+        let session = Session::at(&path);
+
+        let first = runtime();
+        let state = State {
+            debug: false,
+            ..State::test()
+        };
+        let mut rl = Lines(vec!["mkdir a", "cd /a", "checkpoint other"]);
+        interpreter_loop(state, &first, &mut rl, &mut Ignore, Some(&session)).unwrap();
+
+        let history = session.load().unwrap();
+        assert_eq!(history.branch(), "other");
+        assert_eq!(history.current().pwd, "/a");
+        assert!(!history.current().debug);
+        assert_eq!(
+            history.current().dockerfile(),
+            "FROM alpine:edge\nRUN mkdir a\nWORKDIR /a"
+        );
+
+        let commits = first.commits();
+        assert_eq!(commits[1].comment, "WORKDIR /a");
+        assert_eq!(commits[1].changes, vec!["WORKDIR /a"]);
+
+        // Images still there: nothing to rebuild.
+        resume_loop(&session, &first, &mut Lines(vec!["ls"]), &mut Ignore).unwrap();
+        assert!(first.builds().is_empty());
+        assert_eq!(first.runs().last().unwrap().0, commits[1].tag);
+
+        // Images pruned: rebuilt before carrying on.
+        let second = runtime();
+        let mut rl = Lines(vec!["checkout main", "ls"]);
+        resume_loop(&session, &second, &mut rl, &mut Ignore).unwrap();
+        let builds = second.builds();
+        assert_eq!(builds.len(), 2);
+        assert_eq!(builds[0].1, "FROM alpine:edge\nRUN mkdir a");
+        assert_eq!(builds[1].1, "FROM alpine:edge\nRUN mkdir a\nWORKDIR /a");
+        assert_eq!(second.runs().last().unwrap().0, builds[1].0);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn commands_over_several_lines_are_saved_and_loaded() {
+        let commands = [
+            "echo 'one\ntwo' > /f",
+            "apk add \\\n  curl",
+            "cat <<EOF >/greeting\nhello\nEOF",
+            "cat <<EOF >/a\nEOF\nEOF\necho 'b\nc'",
+        ];
+        let mut state = State::test();
+        state
+            .lines
+            .extend(commands.iter().map(|command| Instruction::run(command)));
+        let path =
+            std::env::temp_dir().join(format!("dockershell-lines-{}.json", std::process::id()));
+        let session = Session::at(&path);
+        session.save(&History::new(state.clone())).unwrap();
+
+        let loaded = session.load().unwrap();
+        assert_eq!(loaded.current().lines, state.lines);
+        let typed: Vec<String> = loaded.current().lines[1..]
+            .iter()
+            .filter_map(Instruction::to_shell)
+            .collect();
+        assert_eq!(typed, commands);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn dockerfile_instructions_are_run_before_the_prompt() {
+        let runtime = FakeRuntime::new()
+            .on(
+                "mkdir a",
+                FakeCommand::default().changes(vec![Change {
+                    path: "/a".to_owned(),
+                    kind: ChangeKind::Added,
+                }]),
+            )
+            .on("cd /a", FakeCommand::default().cd("/a"))
+            .on("export A=b", FakeCommand::default().export("A", "b"));
+        let greeting =
+            std::env::temp_dir().join(format!("dockershell-f-{}.txt", std::process::id()));
+        std::fs::write(&greeting, "hello").unwrap();
+        let text = format!(
+            "FROM alpine:edge\nRUN mkdir a\nUSER nobody\nWORKDIR /a\nCOPY {} greeting\n\
+             ENV A=b\nLABEL a=b",
+            greeting.display()
+        );
+        let path = std::env::temp_dir().join(format!("dockershell-f-{}.json", std::process::id()));
+        let session = Session::at(&path);
+        let state = State {
+            lines: parse_dockerfile(&text).unwrap(),
+            ..State::test()
+        };
+        interpreter_loop(
+            state,
+            &runtime,
+            &mut Lines(vec![]),
+            &mut Ignore,
+            Some(&session),
+        )
+        .unwrap();
+
+        let history = session.load().unwrap();
+        let lines: Vec<String> = history
+            .current()
+            .lines
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        assert_eq!(lines.len(), 7);
+        assert_eq!(
+            lines[..4],
+            [
+                "FROM alpine:edge",
+                "RUN mkdir a",
+                "USER nobody",
+                "WORKDIR /a"
+            ]
+        );
+        assert!(lines[4].starts_with("COPY ") && lines[4].ends_with(" greeting"));
+        assert_eq!(lines[5..], ["ENV A=b", "LABEL a=b"]);
+        assert_eq!(history.current().pwd, "/a");
+        assert_eq!(
+            history.current().layer_changes[&4],
+            vec![FileChange::new("/a/greeting", ChangeKind::Added, Some(5))]
+        );
+        let built: Vec<String> = runtime.builds().into_iter().map(|(_, text)| text).collect();
+        assert!(built.iter().any(|text| text.ends_with("\nUSER nobody")));
+        assert!(built.iter().any(|text| text.ends_with("\nLABEL a=b")));
+
+        std::fs::remove_file(&greeting).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sessions_started_together_get_their_own_files() {
+        let dir = std::env::temp_dir().join(format!("dockershell-sessions-{}", std::process::id()));
+        let first = Session::create_in(&dir).unwrap();
+        let second = Session::create_in(&dir).unwrap();
+        assert_ne!(first.name(), second.name());
+        assert_ne!(first.context().dir(), second.context().dir());
+        assert!(first.path().is_file() && second.path().is_file());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn edits_are_copied_from_the_session_context() {
+        let runtime = || {
+            FakeRuntime::new()
+                .file("/etc/motd", "hello\n")
+                .on("cd /etc", FakeCommand::default().cd("/etc"))
+        };
+        let path =
+            std::env::temp_dir().join(format!("dockershell-edit-{}.json", std::process::id()));
+        let session = Session::at(&path);
+
+        let first = runtime();
+        let state = State {
+            debug: false,
+            editor: Some("sed -i s/hello/goodbye/".to_owned()),
+            ..State::test()
+        };
+        // The second file is new, and left empty: nothing to record.
+        let mut rl = Lines(vec!["cd /etc", "edit motd", "edit /etc/issue"]);
+        interpreter_loop(state, &first, &mut rl, &mut Ignore, Some(&session)).unwrap();
+
+        let copy = "COPY edit-1/motd /etc/motd";
+        let builds = first.builds();
+        assert_eq!(builds.len(), 1);
+        assert_eq!(
+            builds[0].1,
+            format!("FROM {}\n{}", first.commits()[0].tag, copy)
+        );
+        let edited = vec![("edit-1/motd".to_owned(), b"goodbye\n".to_vec())];
+        assert_eq!(first.built_files(), edited);
+        let history = session.load().unwrap();
+        assert_eq!(history.current().last_instruction().to_string(), copy);
+
+        // Rebuilt from the same context when resumed.
+        let second = runtime();
+        resume_loop(&session, &second, &mut Lines(vec![]), &mut Ignore).unwrap();
+        let builds = second.builds();
+        assert_eq!(
+            builds.last().unwrap().1,
+            "FROM alpine:edge\nWORKDIR /etc\n".to_owned() + copy
+        );
+        assert!(second.built_files().ends_with(&edited));
+
+        std::fs::remove_dir_all(session.context().dir()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn copies_are_built_and_exported_with_their_context() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = std::env::temp_dir().join(format!("dockershell-copy-{}", std::process::id()));
+        let (source, export) = (tmp.join("app"), tmp.join("export"));
+        std::fs::create_dir_all(source.join("conf")).unwrap();
+        std::fs::write(source.join("run.sh"), "exec app\n").unwrap();
+        std::fs::write(source.join("conf").join("app.ini"), "[app]\n").unwrap();
+
+        let runtime = FakeRuntime::new();
+        let copy = format!("copy --chmod=755 {} /srv", source.display());
+        let export_dir = export.display().to_string();
+        let mut rl = Lines(vec![
+            &copy,
+            "copy /no/such/file /srv",
+            "copy --chmod=999 . /srv",
+            "exit",
+            &export_dir,
+        ]);
+        interpreter_loop(State::test(), &runtime, &mut rl, &mut Ignore, None).unwrap();
+
+        // Built without --chmod, which needs BuildKit; the context has the modes.
+        let builds = runtime.builds();
+        assert_eq!(builds.len(), 1);
+        assert_eq!(builds[0].1, "FROM alpine:edge\nCOPY copy-1/app /srv");
+        let mut files = runtime.built_files();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                ("copy-1/app/conf/app.ini".to_owned(), b"[app]\n".to_vec()),
+                ("copy-1/app/run.sh".to_owned(), b"exec app\n".to_vec()),
+            ]
+        );
+
+        let dockerfile = std::fs::read_to_string(export.join("Dockerfile")).unwrap();
+        assert_eq!(
+            dockerfile,
+            "FROM alpine:edge\nCOPY --chmod=755 copy-1/app /srv\n"
+        );
+        let run = std::fs::metadata(export.join("copy-1/app/run.sh")).unwrap();
+        assert_eq!(run.permissions().mode() & 0o7777, 0o755);
+
+        std::fs::remove_dir_all(&tmp).unwrap();
+    }
+
+    /// Lines to read, and the prompts they were read at.
+    struct Prompted<'a>(Vec<&'a str>, Vec<String>);
+
+    impl<'a> ReadPrompt for Prompted<'a> {
+        fn read_line(&mut self, prompt: &str) -> Result<String, ReadlineError> {
+            self.1.push(prompt.to_owned());
+            if self.0.is_empty() {
+                return Err(ReadlineError::Eof);
+            }
+            Ok(self.0.remove(0).to_owned())
+        }
+
+        fn add_history_entry(&mut self, _val: &str) {}
+    }
+
+    #[test]
+    fn layers_build_while_the_history_is_looked_at() {
+        let tmp = std::env::temp_dir().join(format!("dockershell-build-{}", std::process::id()));
+        std::fs::create_dir_all(&tmp).unwrap();
+        let motd = tmp.join("motd");
+        std::fs::write(&motd, "hello\n").unwrap();
+
+        let runtime = FakeRuntime::new()
+            .builds_take(Duration::from_millis(500))
+            .commits_take(Duration::from_millis(500))
+            .on("mkdir /srv", FakeCommand::adds("/srv"));
+        let copy = format!("copy {} /etc/motd", motd.display());
+        let lines = vec![
+            &copy,
+            "layers",
+            "?ls /etc",
+            "mkdir /srv",
+            "layers",
+            "ls /srv",
+        ];
+        let mut rl = Prompted(lines, vec![]);
+        interpreter_loop(State::test(), &runtime, &mut rl, &mut Ignore, None).unwrap();
+
+        let building: Vec<Option<usize>> =
+            rl.1.iter()
+                .map(|prompt| {
+                    (1..3).find(|layer| prompt.contains(&format!("[building layer {}]", layer)))
+                })
+                .collect();
+        let layers = vec![None, Some(1), Some(1), None, Some(2), Some(2), None];
+        assert_eq!(building, layers);
+        // Only the history was looked at before each layer was waited for.
+        let built = runtime.builds()[0].0.clone();
+        let committed = runtime.commits()[0].tag.clone();
+        let runs = runtime.runs();
+        assert_eq!(runs[runs.len() - 3], (built.clone(), "ls /etc".to_owned()));
+        assert_eq!(runs[runs.len() - 2], (built, "mkdir /srv".to_owned()));
+        assert_eq!(runs[runs.len() - 1], (committed, "ls /srv".to_owned()));
+
+        std::fs::remove_dir_all(&tmp).unwrap();
+    }
+
+    struct Failures(Vec<(Instruction, String)>);
+
+    impl ExecListener for Failures {
+        fn command_run(&mut self, _: &str, _: &State, _: Result<&LineResult, &Error>) {}
+
+        fn layer_failed(&mut self, instruction: &Instruction, err: &Error) {
+            self.0.push((instruction.clone(), err.to_string()));
+        }
+    }
+
+    #[test]
+    fn failed_builds_are_rolled_back() {
+        let tmp = std::env::temp_dir().join(format!("dockershell-fail-{}", std::process::id()));
+        std::fs::create_dir_all(&tmp).unwrap();
+        let motd = tmp.join("motd");
+        std::fs::write(&motd, "hello\n").unwrap();
+
+        let copied = "COPY copy-1/motd /etc/motd";
+        let runtime = FakeRuntime::new().build_fails(copied, "no space left\n", "copy failed");
+        let copy = format!("copy {} /etc/motd", motd.display());
+        let mut rl = Lines(vec![&copy, "cat /etc/motd"]);
+        let mut failures = Failures(vec![]);
+        interpreter_loop(State::test(), &runtime, &mut rl, &mut failures, None).unwrap();
+
+        assert_eq!(
+            failures.0,
+            vec![(
+                Instruction::Copy {
+                    flags: vec![],
+                    sources: vec!["copy-1/motd".to_owned()],
+                    dest: "/etc/motd".to_owned(),
+                    heredocs: vec![],
+                },
+                format!("Step 2/2 : {} failed: copy failed", copied)
+            )]
+        );
+        // Back on the image from before.
+        let last_run = runtime.runs().last().unwrap().clone();
+        assert_eq!(
+            last_run,
+            ("alpine:edge".to_owned(), "cat /etc/motd".to_owned())
+        );
+
+        std::fs::remove_dir_all(&tmp).unwrap();
+    }
+
+    #[test]
+    fn get_keeps_hostile_archives_in_their_place() {
+        let tmp = std::env::temp_dir().join(format!("dockershell-hostile-{}", std::process::id()));
+        let outside = tmp.join("outside");
+        let dest = tmp.join("dest");
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::create_dir_all(&dest).unwrap();
+        std::fs::write(tmp.join("secret"), "secret\n").unwrap();
+
+        let tar = |entries: &[(&str, tar::EntryType, &str)]| {
+            let mut tar = tar::Builder::new(vec![]);
+            for (path, kind, link) in entries {
+                let mut header = tar::Header::new_gnu();
+                header.set_path(path).unwrap();
+                header.set_entry_type(*kind);
+                header.set_mode(0o755);
+                if !link.is_empty() {
+                    header.set_link_name(link).unwrap();
+                }
+                let contents: &[u8] = if kind.is_file() { b"pwned\n" } else { b"" };
+                header.set_size(contents.len() as u64);
+                header.set_cksum();
+                tar.append(&header, contents).unwrap();
+            }
+            tar.into_inner().unwrap()
+        };
+        let outside_link = outside.display().to_string();
+        let runtime = FakeRuntime::new()
+            .archive(
+                "/through-symlink",
+                tar(&[
+                    ("app", tar::EntryType::Directory, ""),
+                    ("app/escape", tar::EntryType::Symlink, &outside_link),
+                    ("app/escape/pwned", tar::EntryType::Regular, ""),
+                ]),
+            )
+            .archive(
+                "/absolute-link",
+                tar(&[
+                    ("app", tar::EntryType::Directory, ""),
+                    ("app/secret", tar::EntryType::Link, "/etc/hostname"),
+                ]),
+            )
+            .archive(
+                "/parent-link",
+                tar(&[
+                    ("app", tar::EntryType::Directory, ""),
+                    ("app/secret", tar::EntryType::Link, "app/../../secret"),
+                ]),
+            );
+        let state = State {
+            pwd: "/".to_owned(),
+            ..State::test()
+        };
+        let scratch = ScratchContainers::new();
+        for path in &["/through-symlink", "/absolute-link", "/parent-link"] {
+            let line = format!("get {} {}", path, dest.display());
+            assert!(
+                parse_line(&line, &state, &runtime, &scratch).is_err(),
+                "{}",
+                line
+            );
+        }
+        assert!(!outside.join("pwned").exists());
+        assert!(!dest.join("app").join("secret").exists());
+        // Nothing is left half unpacked either.
+        assert_eq!(std::fs::read_dir(&dest).unwrap().count(), 0);
+
+        scratch.remove_all(&runtime).unwrap();
+        std::fs::remove_dir_all(&tmp).unwrap();
+    }
+
+    #[test]
+    fn get_copies_files_out_without_recording() {
+        let runtime = FakeRuntime::new()
+            .file("/etc/motd", "hello\n")
+            .file("/var/log/app/a.log", "a\n")
+            .file("/var/log/app/old/b.log", "b\n");
+        let state = State {
+            pwd: "/var/log".to_owned(),
+            ..State::test()
+        };
+        let scratch = ScratchContainers::new();
+        let tmp = std::env::temp_dir().join(format!("dockershell-get-{}", std::process::id()));
+        std::fs::create_dir_all(&tmp).unwrap();
+        let get = |line: String| parse_line(&line, &state, &runtime, &scratch).map(|(r, _)| r);
+
+        // Into an existing directory, and to a new name.
+        let no_op = LineResult::NoOp(String::new());
+        assert_eq!(
+            get(format!("get /etc/motd {}", tmp.display())).unwrap(),
+            no_op
+        );
+        let logs = tmp.join("logs");
+        assert_eq!(get(format!("get app {}", logs.display())).unwrap(), no_op);
+        let read = |path: &std::path::Path| std::fs::read_to_string(path).unwrap();
+        assert_eq!(read(&tmp.join("motd")), "hello\n");
+        assert_eq!(read(&logs.join("a.log")), "a\n");
+        assert_eq!(read(&logs.join("old").join("b.log")), "b\n");
+
+        match get(format!("get /nope {}", tmp.display())) {
+            Err(Error::FileNotFound(_)) => {}
+            other => panic!("{:?}", other),
+        }
+        assert!(runtime.commands().is_empty());
+        assert!(runtime.builds().is_empty() && runtime.commits().is_empty());
+
+        scratch.remove_all(&runtime).unwrap();
+        std::fs::remove_dir_all(&tmp).unwrap();
+    }
+
+    #[test]
+    fn layers_keep_what_they_changed() {
+        let change = |path: &str, kind| Change {
+            path: path.to_owned(),
+            kind,
+        };
+        let runtime = FakeRuntime::new()
+            .file("/usr/bin/curl", &"x".repeat(3000))
+            .on(
+                "apk add curl",
+                FakeCommand::default().changes(vec![
+                    change("/usr/bin/curl", ChangeKind::Added),
+                    change("/usr/bin", ChangeKind::Modified),
+                ]),
+            )
+            .on(
+                "rm /usr/bin/curl",
+                FakeCommand::default().changes(vec![change("/usr/bin/curl", ChangeKind::Deleted)]),
+            );
+        let scratch = ScratchContainers::new();
+        let run = |line: &str, state: &State| match parse_line(line, state, &runtime, &scratch) {
+            Ok((LineResult::State(state, _), _)) => state,
+            other => panic!("{}: {:?}", line, other.map(|(result, _)| result)),
+        };
+
+        let state = State {
+            pwd: "/".to_owned(),
+            ..State::test()
+        };
+        let installed = run("apk add curl", &state);
+        let removed = run("rm /usr/bin/curl", &installed);
+        let mut expected = BTreeMap::new();
+        expected.insert(
+            1,
+            vec![
+                FileChange::new("/usr/bin", ChangeKind::Modified, None),
+                FileChange::new("/usr/bin/curl", ChangeKind::Added, Some(3000)),
+            ],
+        );
+        assert_eq!(installed.layer_changes, expected);
+        expected.insert(
+            2,
+            vec![FileChange::new("/usr/bin/curl", ChangeKind::Deleted, None)],
+        );
+        assert_eq!(removed.layer_changes, expected);
+
+        let no_op = LineResult::NoOp(String::new());
+        for line in &["diff", "diff 1", "diff 0 2"] {
+            let result = parse_line(line, &removed, &runtime, &scratch).map(|(r, _)| r);
+            assert_eq!(result.unwrap(), no_op, "{}", line);
+        }
+        for line in &["diff 3", "diff 2 1"] {
+            match parse_line(line, &removed, &runtime, &scratch) {
+                Err(Error::BadArgument(_)) => {}
+                other => panic!("{}: {:?}", line, other.map(|(result, _)| result)),
+            }
+        }
+        // Not layer numbers: diff(1).
+        parse_line("diff a b", &removed, &runtime, &scratch).unwrap();
+        assert_eq!(runtime.commands().last().unwrap(), "diff a b");
+        scratch.remove_all(&runtime).unwrap();
+    }
+
+    #[test]
+    fn ignored_changes_are_not_recorded() {
+        let history = vec![
+            Change {
+                path: "/root/.ash_history".to_owned(),
+                kind: ChangeKind::Added,
+            },
+            Change {
+                path: "/root".to_owned(),
+                kind: ChangeKind::Modified,
+            },
+        ];
+        let mut built = history.clone();
+        built.push(Change {
+            path: "/app".to_owned(),
+            kind: ChangeKind::Added,
+        });
+        let runtime = FakeRuntime::new()
+            .on("history", FakeCommand::default().changes(history))
+            .on("make", FakeCommand::default().changes(built));
+        let scratch = ScratchContainers::new();
+        let state = State {
+            ignore: IgnoreRules {
+                enabled: true,
+                patterns: vec!["/root/.*_history".to_owned()],
+            },
+            pwd: "/".to_owned(),
+            ..State::test()
+        };
+        let result =
+            |line: &str, state: &State| parse_line(line, state, &runtime, &scratch).unwrap().0;
+
+        let no_op = LineResult::NoOp(String::new());
+        assert_eq!(result("history", &state), no_op);
+        // Not the image anymore, so not run in again.
+        assert_eq!(runtime.containers(), 0);
+        match result("make", &state) {
+            LineResult::State(recorded, _) => {
+                let paths: Vec<&str> = recorded.layer_changes[&1]
+                    .iter()
+                    .map(|change| change.path.as_str())
+                    .collect();
+                assert_eq!(paths, vec!["/app"]);
+            }
+            other => panic!("make: {:?}", other),
+        }
+        assert_eq!(result("ignore", &state), no_op);
+        let off = match result("ignore off", &state) {
+            LineResult::State(off, _) => off,
+            other => panic!("ignore off: {:?}", other),
+        };
+        assert!(!off.ignore.enabled);
+        match result("history", &off) {
+            LineResult::State(recorded, _) => {
+                assert_eq!(recorded.lines.last().unwrap().to_string(), "RUN history")
+            }
+            other => panic!("history: {:?}", other),
+        }
+        scratch.remove_all(&runtime).unwrap();
+    }
+
+    #[test]
+    fn heredoc_commands_are_recorded_and_replayed() {
+        let command = "cat <<EOF >/greeting\nhello\nEOF";
+        let runtime = FakeRuntime::new().on(
+            command,
+            FakeCommand::default().changes(vec![Change {
+                path: "/greeting".to_owned(),
+                kind: ChangeKind::Added,
+            }]),
+        );
+        let scratch = ScratchContainers::new();
+        let state = State {
+            pwd: "/".to_owned(),
+            ..State::test()
+        };
+        let recorded = match parse_line(command, &state, &runtime, &scratch) {
+            Ok((LineResult::State(state, _), Some(_))) => state.last_instruction().clone(),
+            other => panic!("{:?}", other.map(|(result, _)| result)),
+        };
+        // In a form the classic builder takes.
+        assert_eq!(
+            recorded.to_string(),
+            "RUN [\"/bin/sh\", \"-c\", \"cat <<EOF >/greeting\\nhello\\nEOF\"]"
+        );
+        scratch.remove_all(&runtime).unwrap();
+
+        // Read back from a Dockerfile, it is typed again as it was.
+        let text = format!("FROM alpine:edge\n{}\n", recorded);
+        let parsed = parse_dockerfile(&text).unwrap();
+        assert_eq!(parsed[1].to_shell(), Some(command.to_owned()));
+    }
+
+    #[test]
+    fn commands_over_several_lines_are_rebuilt() {
+        let command = "echo 'one\ntwo' > /f";
+        let runtime = FakeRuntime::new()
+            .on("mkdir c", FakeCommand::adds("/c"))
+            .on(command, FakeCommand::adds("/f"));
+        let mut state = State {
+            lines: vec![Instruction::from_image("alpine:edge")],
+            ..State::test()
+        };
+        for line in &["mkdir c", command, "undo 1"] {
+            state.lines.push(Instruction::run(line));
+        }
+
+        interpreter_loop_from_file(state, &runtime, &mut Ignore).unwrap();
+
+        let builds = runtime.builds();
+        assert_eq!(builds.len(), 1);
+        assert_eq!(
+            builds[0].1,
+            "FROM alpine:edge\nRUN [\"/bin/sh\", \"-c\", \"echo 'one\\ntwo' > /f\"]"
+        );
+        assert_eq!(
+            parse_dockerfile(&builds[0].1).unwrap()[1].to_shell(),
+            Some(command.to_owned())
+        );
+    }
+
+    #[test]
+    fn parse_dockerfile_forms() {
+        let text = "# syntax=docker/dockerfile:1\n\
+                    # a comment\n\
+                    ARG BASE=alpine:edge\n\
+                    \n\
+                    from ${BASE} AS build\n\
+                    RUN apk add \\\n\
+                    # dropped\n\
+                    \x20   curl\n\
+                    COPY --chown=app:app src /src\n\
+                    CMD [\"/bin/sh\", \"-c\", \"ls\"]\n\
+                    RUN <<EOF\n\
+                    echo hi\n\
+                    EOF\n";
+        let parsed = dockerfile::parse(text).unwrap();
+
+        assert_eq!(
+            parsed.directives,
+            vec![("syntax".to_owned(), "docker/dockerfile:1".to_owned())]
+        );
+        assert_eq!(parsed.base_image(), Some("alpine:edge".to_owned()));
+        let keywords: Vec<&str> = parsed.nodes.iter().map(|n| n.keyword.as_str()).collect();
+        assert_eq!(keywords, vec!["ARG", "FROM", "RUN", "COPY", "CMD", "RUN"]);
+
+        let run = &parsed.nodes[2];
+        assert_eq!(run.args, Arguments::Shell("apk add     curl".to_owned()));
+        assert_eq!(
+            run.span,
+            Span {
+                start_line: 6,
+                end_line: 8
+            }
+        );
+        assert_eq!(parsed.nodes[3].flags, vec!["--chown=app:app".to_owned()]);
+        assert_eq!(
+            parsed.nodes[4].args,
+            Arguments::Exec(vec!["/bin/sh".to_owned(), "-c".to_owned(), "ls".to_owned()])
+        );
+        let heredoc = &parsed.nodes[5];
+        assert_eq!(heredoc.heredocs[0].body, "echo hi\n");
+        assert_eq!(
+            heredoc.span,
+            Span {
+                start_line: 11,
+                end_line: 13
+            }
+        );
+        assert_eq!(heredoc.to_string(), "RUN <<EOF\necho hi\nEOF");
+    }
+
+    #[test]
+    fn parse_dockerfile_errors() {
+        assert_eq!(
+            dockerfile::parse("").unwrap_err().message,
+            "no FROM instruction"
+        );
+        let err = dockerfile::parse("FROM alpine\nFETCH x\n").unwrap_err();
+        assert_eq!(err.line, 2);
+        let err = dockerfile::parse("RUN ls\nFROM alpine\n").unwrap_err();
+        assert_eq!(err.line, 1);
+        let err = dockerfile::parse("FROM alpine\nRUN <<EOF\necho\n").unwrap_err();
+        assert_eq!(err.line, 2);
+        let stages = dockerfile::parse("FROM alpine AS build\nRUN make\nFROM build\n").unwrap();
+        assert_eq!(stages.stage().unwrap_err().line, 3);
+        assert_eq!(stages.base_image(), None);
+    }
+
+    #[test]
+    fn heredocs_start_at_unquoted_redirections() {
+        let parsed = dockerfile::parse(
+            "FROM alpine\n\
+             RUN echo $((1<<2)) \"<<EOF\" '<<EOF' # <<EOF\n\
+             RUN cat<<EOF >/a && cat <<-'END' >/b\n\
+             a\n\
+             EOF\n\
+             \tb\n\
+             \tEND\n",
+        )
+        .unwrap();
+        assert_eq!(parsed.nodes.len(), 3);
+        assert!(parsed.nodes[1].heredocs.is_empty());
+        let heredocs = &parsed.nodes[2].heredocs;
+        assert_eq!(heredocs.len(), 2);
+        assert_eq!(heredocs[0].delimiter, "EOF");
+        assert_eq!(heredocs[0].body, "a\n");
+        assert_eq!(heredocs[1].delimiter, "END");
+        assert_eq!(heredocs[1].body, "b\n");
+    }
+
+    #[test]
+    fn parse_dockerfile_escape_directive() {
+        let parsed = dockerfile::parse("# escape=`\nFROM alpine\nRUN dir `\n  c:\\\n").unwrap();
+        assert_eq!(parsed.escape, '`');
+        assert_eq!(
+            parsed.nodes[1].args,
+            Arguments::Shell("dir   c:\\".to_owned())
+        );
+    }
+
+    #[test]
+    fn instructions_are_typed() {
+        let instructions = parse_dockerfile(
+            "FROM --platform=linux/amd64 alpine:edge AS base\n\
+             ENV PATH=/opt/bin:$PATH GREETING=\"hello world\"\n\
+             ENV LEGACY some value\n\
+             COPY --chown=app:app a b /dest/\n\
+             WORKDIR /src\n\
+             CMD [\"sh\"]\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            instructions[0],
+            Instruction::From {
+                flags: vec!["--platform=linux/amd64".to_owned()],
+                image: "alpine:edge".to_owned(),
+                alias: Some("base".to_owned()),
+            }
+        );
+        assert_eq!(
+            instructions[1],
+            Instruction::Env(vec![
+                ("PATH".to_owned(), "/opt/bin:$PATH".to_owned()),
+                ("GREETING".to_owned(), "hello world".to_owned()),
+            ])
+        );
+        assert_eq!(
+            instructions[2],
+            Instruction::Env(vec![("LEGACY".to_owned(), "some value".to_owned())])
+        );
+        assert_eq!(
+            instructions[3],
+            Instruction::Copy {
+                flags: vec!["--chown=app:app".to_owned()],
+                sources: vec!["a".to_owned(), "b".to_owned()],
+                dest: "/dest/".to_owned(),
+                heredocs: vec![],
+            }
+        );
+        assert_eq!(instructions[4], Instruction::workdir("/src"));
+        assert_eq!(instructions[5].keyword(), "CMD");
+    }
+
+    #[test]
+    fn instructions_round_trip() {
+        let instructions = parse_dockerfile(
+            "FROM alpine:edge\n\
+             ENV A=\"x \\\"y\\\"\" B=\n\
+             COPY [\"my file\", \"/dest dir/\"]\n\
+             RUN [\"echo\", \"hi\"]\n\
+             RUN <<EOF\n\
+             echo hi\n\
+             EOF\n\
+             ARG VERSION=1.0\n\
+             USER nobody\n\
+             LABEL a=b\n",
+        )
+        .unwrap();
+
+        let text = to_dockerfile(&instructions);
+        assert_eq!(parse_dockerfile(&text).unwrap(), instructions);
+
+        // Variables are left for docker to substitute.
+        let text = "FROM alpine:edge\nENV PATH=/x:$PATH HOME_PATH=\"\\$HOME/bin x\"";
+        assert_eq!(to_dockerfile(&parse_dockerfile(text).unwrap()), text);
+    }
+
+    #[test]
+    fn errors_say_why_a_line_failed() {
+        let runtime = FakeRuntime::new();
+        let state = State {
+            image_name: "nosuchimage".to_owned(),
+            ..State::test()
+        };
+        let result =
+            parse_line("ls", &state, &runtime, &ScratchContainers::new()).map(|(result, _)| result);
+        match result {
+            Err(Error::ImageMissing(image)) => assert_eq!(image, "nosuchimage"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
 }